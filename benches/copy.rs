@@ -0,0 +1,134 @@
+//! Compares the sync's own walker and copier, `sync::sync_directories` run in-process, with
+//! `fs_extra::dir::copy_with_progress`, which it replaced, on a tree of many small files and one
+//! of a few large ones.
+//!
+//! Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use emudeck_sync::compare::CompareBy;
+use emudeck_sync::copy::CopyOptions;
+use emudeck_sync::delete::DeleteOptions;
+use emudeck_sync::filter::PathFilter;
+use emudeck_sync::layout::Layout;
+use emudeck_sync::manifest::Manifest;
+use emudeck_sync::sync::{self, SyncOptions};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Saves, states and configs: lots of files of a few KiB.
+const SMALL_FILES: usize = 2000;
+const SMALL_FILE_SIZE: usize = 4 * 1024;
+const SMALL_FILES_PER_FOLDER: usize = 100;
+
+/// ROMs and disc images, scaled down to keep a run short.
+const LARGE_FILES: usize = 4;
+const LARGE_FILE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Writes `count` files of `size` bytes below a new temporary directory, `per_folder` to a
+/// folder. The content varies so that nothing can be shortcut by a filesystem that dedupes.
+fn synthetic_tree(count: usize, size: usize, per_folder: usize) -> TempDir {
+    let tree = tempfile::tempdir().expect("create source tree");
+    for index in 0..count {
+        let folder = tree.path().join(format!("folder{:03}", index / per_folder));
+        fs::create_dir_all(&folder).expect("create source folder");
+        let content: Vec<u8> = (0..size).map(|byte| (byte ^ index) as u8).collect();
+        fs::write(folder.join(format!("file{index:05}.bin")), content).expect("write source file");
+    }
+    tree
+}
+
+fn copy_with_fs_extra(source: &Path, destination: &Path) {
+    let options = fs_extra::dir::CopyOptions {
+        content_only: true,
+        ..fs_extra::dir::CopyOptions::new()
+    };
+    fs_extra::dir::copy_with_progress(source, destination, &options, |_| {
+        fs_extra::dir::TransitProcessResult::ContinueOrAbort
+    })
+    .expect("fs_extra copy");
+}
+
+/// What a plain run does: existence checks against the destination, no deletes or moves.
+fn sync_options() -> SyncOptions {
+    SyncOptions {
+        copy: CopyOptions::default(),
+        compare_by: CompareBy::Existence,
+        verify: false,
+        verify_unchanged: false,
+        ignore_space: true,
+        folder_order: BTreeMap::new(),
+        max_errors: 0,
+        delete_extraneous: false,
+        detect_moves: false,
+        delete: DeleteOptions::default(),
+        ignored: Vec::new(),
+        battery: None,
+        strict: true,
+        sync_empty_dirs: false,
+        layout: Layout::default(),
+        one_file_system: false,
+        filter: PathFilter::default(),
+        checksum_cache: None,
+        source_not_authoritative: false,
+        deadline: None,
+    }
+}
+
+fn copy_with_emudeck_sync(options: &SyncOptions, source: &Path, destination: &Path) {
+    sync::sync_directories(options, source, destination, &mut Manifest::new())
+        .expect("emudeck_sync copy");
+}
+
+/// A fresh destination for every sample, so each one copies the whole tree.
+fn empty_destination() -> TempDir {
+    tempfile::tempdir().expect("create destination")
+}
+
+fn bench_tree(c: &mut Criterion, name: &str, tree: &TempDir, throughput: Throughput) {
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+    group.throughput(throughput);
+    group.bench_function("fs_extra", |b| {
+        b.iter_batched(
+            empty_destination,
+            |destination| copy_with_fs_extra(tree.path(), destination.path()),
+            BatchSize::PerIteration,
+        )
+    });
+    let options = sync_options();
+    group.bench_function("emudeck_sync", |b| {
+        b.iter_batched(
+            empty_destination,
+            |destination| copy_with_emudeck_sync(&options, tree.path(), destination.path()),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+/// Reported in files per second, which shows the per-file overhead.
+fn small_files(c: &mut Criterion) {
+    let tree = synthetic_tree(SMALL_FILES, SMALL_FILE_SIZE, SMALL_FILES_PER_FOLDER);
+    bench_tree(
+        c,
+        "many_small_files",
+        &tree,
+        Throughput::Elements(SMALL_FILES as u64),
+    );
+}
+
+/// Reported in bytes per second.
+fn large_files(c: &mut Criterion) {
+    let tree = synthetic_tree(LARGE_FILES, LARGE_FILE_SIZE, LARGE_FILES);
+    bench_tree(
+        c,
+        "few_large_files",
+        &tree,
+        Throughput::Bytes((LARGE_FILES * LARGE_FILE_SIZE) as u64),
+    );
+}
+
+criterion_group!(benches, small_files, large_files);
+criterion_main!(benches);
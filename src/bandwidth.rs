@@ -0,0 +1,166 @@
+use crate::units::{format_bytes, format_bytes_exact, parse_bytes};
+use chrono::Timelike;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How often the schedule is consulted again, so a window boundary takes effect mid-copy.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A time-of-day window with its own rate limit, written `08:00-23:00=2MiB`. A window whose end
+/// is before its start runs past midnight. The rate `unlimited` lifts the limit.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct RateWindow {
+    /// Minutes after local midnight.
+    start: u32,
+    end: u32,
+    /// Bytes per second, `None` for unlimited.
+    rate: Option<u64>,
+}
+
+impl TryFrom<String> for RateWindow {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, String> {
+        parse_rate_window(&spec)
+    }
+}
+
+impl fmt::Display for RateWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}=",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )?;
+        match self.rate {
+            Some(rate) => f.write_str(&format_bytes_exact(rate)),
+            None => f.write_str("unlimited"),
+        }
+    }
+}
+
+impl From<RateWindow> for String {
+    fn from(window: RateWindow) -> String {
+        window.to_string()
+    }
+}
+
+pub fn parse_rate_window(spec: &str) -> Result<RateWindow, String> {
+    let invalid = || format!("{spec} is not a rate window, expected e.g. 08:00-23:00=2MiB");
+    let (times, rate) = spec.split_once('=').ok_or_else(invalid)?;
+    let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+    let rate = match rate.trim() {
+        "unlimited" => None,
+        rate => Some(parse_bytes(rate)?),
+    };
+    Ok(RateWindow {
+        start: parse_time_of_day(start).ok_or_else(invalid)?,
+        end: parse_time_of_day(end).ok_or_else(invalid)?,
+        rate,
+    })
+}
+
+fn parse_time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+impl RateWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// The rate limit over the day: the first window containing the current local time decides, and
+/// outside every window `--max-rate` applies.
+#[derive(Clone)]
+pub struct BandwidthSchedule {
+    pub windows: Vec<RateWindow>,
+    pub default_rate: Option<u64>,
+}
+
+impl BandwidthSchedule {
+    pub fn is_unlimited(&self) -> bool {
+        self.default_rate.is_none() && self.windows.iter().all(|window| window.rate.is_none())
+    }
+
+    fn current_rate(&self) -> Option<u64> {
+        let now = chrono::Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        self.windows
+            .iter()
+            .find(|window| window.contains(minute))
+            .map_or(self.default_rate, |window| window.rate)
+    }
+}
+
+/// A token bucket holding copies to the schedule's current rate, with up to a second's worth of
+/// burst.
+pub struct RateLimiter {
+    schedule: BandwidthSchedule,
+    rate: Option<u64>,
+    available: f64,
+    refilled: Instant,
+    checked: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: BandwidthSchedule) -> Self {
+        let rate = schedule.current_rate();
+        log_rate(rate);
+        let now = Instant::now();
+        RateLimiter {
+            schedule,
+            rate,
+            available: 0.0,
+            refilled: now,
+            checked: now,
+        }
+    }
+
+    /// Accounts for `bytes` just transferred, sleeping for as long as that overdraws the bucket.
+    pub fn consume(&mut self, bytes: u64) {
+        let now = Instant::now();
+        if now.duration_since(self.checked) >= SCHEDULE_CHECK_INTERVAL {
+            self.checked = now;
+            let rate = self.schedule.current_rate();
+            if rate != self.rate {
+                log_rate(rate);
+                self.rate = rate;
+            }
+        }
+        let Some(rate) = self.rate.filter(|rate| *rate > 0) else {
+            self.refilled = now;
+            return;
+        };
+
+        let rate = rate as f64;
+        let refill = now.duration_since(self.refilled).as_secs_f64() * rate;
+        self.available = (self.available + refill).min(rate) - bytes as f64;
+        self.refilled = now;
+        if self.available < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.available / rate));
+            self.available = 0.0;
+            self.refilled = Instant::now();
+        }
+    }
+}
+
+fn log_rate(rate: Option<u64>) {
+    match rate {
+        Some(rate) => info!("bandwidth limit is now {}/s", format_bytes(rate)),
+        None => info!("bandwidth is now unlimited"),
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Holds heavy copy work back while running on a low battery.
+#[derive(Clone)]
+pub struct BatteryThrottle {
+    /// Charge percentage below which work is paused while discharging.
+    pub threshold: u8,
+}
+
+impl BatteryThrottle {
+    /// Blocks until the machine is charging, or its battery is at or above the threshold. Returns
+    /// immediately on machines without a battery, and false if `deadline` passes first.
+    pub fn wait_for_power(&self, deadline: Option<Instant>) -> bool {
+        let mut paused = false;
+        while let Some(capacity) = self.low_battery() {
+            if !paused {
+                info!(
+                    "battery at {}% and discharging, pausing the sync until plugged in or above {}%",
+                    capacity, self.threshold
+                );
+                paused = true;
+            }
+            let interval = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return false;
+                    }
+                    left.min(CHECK_INTERVAL)
+                }
+                None => CHECK_INTERVAL,
+            };
+            thread::sleep(interval);
+        }
+        if paused {
+            info!("power is back, resuming the sync");
+        }
+        true
+    }
+
+    /// The charge of the emptiest discharging battery, if it is below the threshold.
+    fn low_battery(&self) -> Option<u8> {
+        discharging_capacities(Path::new(POWER_SUPPLY_DIR))
+            .into_iter()
+            .min()
+            .filter(|capacity| *capacity < self.threshold)
+    }
+}
+
+/// Charge percentages of every battery in `power_supply_dir` that is currently discharging.
+fn discharging_capacities(power_supply_dir: &Path) -> Vec<u8> {
+    let Ok(entries) = fs::read_dir(power_supply_dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|supply| read_attribute(supply, "type").as_deref() == Some("Battery"))
+        .filter(|supply| read_attribute(supply, "status").as_deref() == Some("Discharging"))
+        .filter_map(|supply| read_attribute(&supply, "capacity")?.parse().ok())
+        .collect()
+}
+
+fn read_attribute(supply: &Path, attribute: &str) -> Option<String> {
+    fs::read_to_string(supply.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
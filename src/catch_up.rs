@@ -0,0 +1,274 @@
+use crate::copy::{self, Copied};
+use crate::manifest::{self, Manifest, ManifestEntry, Side};
+use crate::paths;
+use crate::sync::{self, SyncError, SyncOptions};
+use crate::telemetry::{ERRORS, EVENTS, PROGRESS};
+use crate::units::format_bytes;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::Path;
+use tracing::{error, info, warn};
+
+/// What catching up on the changes made while we were not running did.
+#[derive(Default)]
+pub struct CatchUpStats {
+    pub files_pulled: usize,
+    pub files_pushed: usize,
+    pub conflicts: usize,
+    /// Files not looked at when `--time-limit` ran out.
+    pub files_left: usize,
+}
+
+/// What `--pull-missing-only` did.
+#[derive(Default)]
+pub struct PullStats {
+    pub files_pulled: usize,
+    pub bytes_pulled: u64,
+    /// Network files left alone as something is already at their local path.
+    pub files_present: usize,
+    pub files_failed: usize,
+    /// Files not looked at when `--time-limit` ran out.
+    pub files_left: usize,
+}
+
+/// Applies changes made while the watcher was not running, judged against what the manifest last
+/// recorded for each file. Files changed only on the network are copied to the local emulation
+/// directory, files changed only locally are copied to the network, and files changed on both
+/// sides are left alone with a warning. Network files the manifest has never seen and that do not
+/// exist locally are treated as created on the network. Nothing is deleted.
+pub fn catch_up(
+    options: &SyncOptions,
+    local: &Path,
+    network: &Path,
+    manifest: &mut Manifest,
+) -> io::Result<CatchUpStats> {
+    let mut stats = CatchUpStats::default();
+    paths::walk_files(
+        network,
+        Path::new(""),
+        &options.ignored,
+        &mut |relative, network_metadata| {
+            if sync::past_deadline(options) {
+                stats.files_left += 1;
+                return Ok(());
+            }
+            if let Err(e) = catch_up_file(
+                options,
+                local,
+                network,
+                relative,
+                network_metadata,
+                manifest,
+                &mut stats,
+            ) {
+                error!(target: ERRORS, "could not catch up on {}: {:?}", relative.display(), e);
+            }
+            Ok::<(), io::Error>(())
+        },
+        &mut |relative, e| sync::skip_unreadable(options.strict, relative, e),
+    )?;
+    info!(target: EVENTS,
+        "caught up: {} files pulled from the network, {} pushed, {} conflicts",
+        stats.files_pulled, stats.files_pushed, stats.conflicts
+    );
+    warn_files_left(stats.files_left);
+    Ok(stats)
+}
+
+/// Copies the network files that do not exist locally, for bootstrapping a new device from the
+/// network without touching anything it already has. A local file is never overwritten, whatever
+/// its content, and nothing is deleted or pushed.
+pub fn pull_missing(
+    options: &SyncOptions,
+    local: &Path,
+    network: &Path,
+    manifest: &mut Manifest,
+) -> Result<PullStats, SyncError> {
+    let mut stats = PullStats::default();
+    paths::walk_files(
+        network,
+        Path::new(""),
+        &options.ignored,
+        &mut |relative, _| {
+            if sync::past_deadline(options) {
+                stats.files_left += 1;
+                return Ok(());
+            }
+            match pull_missing_file(options, local, network, relative, manifest, &mut stats) {
+                Ok(()) => Ok(()),
+                Err(e) if options.strict => Err(SyncError::Io(e)),
+                Err(e) => {
+                    error!(target: ERRORS, "could not pull {}: {:?}", relative.display(), e);
+                    stats.files_failed += 1;
+                    if options.max_errors != 0 && stats.files_failed >= options.max_errors {
+                        return Err(SyncError::TooManyErrors(stats.files_failed));
+                    }
+                    Ok(())
+                }
+            }
+        },
+        &mut |relative, e| {
+            sync::skip_unreadable(options.strict, relative, e).map_err(SyncError::Io)
+        },
+    )?;
+    info!(target: EVENTS,
+        "pulled {} missing files ({}) from the network, {} already present locally, {} failed",
+        stats.files_pulled,
+        format_bytes(stats.bytes_pulled),
+        stats.files_present,
+        stats.files_failed
+    );
+    warn_files_left(stats.files_left);
+    Ok(stats)
+}
+
+fn warn_files_left(files_left: usize) {
+    if files_left > 0 {
+        warn!(target: EVENTS,
+            "time limit reached, leaving {} files for the next run",
+            files_left
+        );
+    }
+}
+
+fn pull_missing_file(
+    options: &SyncOptions,
+    local: &Path,
+    network: &Path,
+    network_relative: &Path,
+    manifest: &mut Manifest,
+    stats: &mut PullStats,
+) -> io::Result<()> {
+    let relative = &options.layout.to_local(network_relative);
+    if !options.filter.allows(relative) {
+        return Ok(());
+    }
+    let local_path = local.join(relative);
+    match fs::symlink_metadata(&local_path) {
+        Ok(_) => {
+            stats.files_present += 1;
+            return Ok(());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    info!(target: PROGRESS, "{} is missing locally, pulling it", relative.display());
+    let network_path = network.join(network_relative);
+    let recorded = manifest
+        .entry(relative)
+        .map(|entry| entry.stored_transforms().to_vec());
+    let copied = copy::copy_from_network(
+        &network_path,
+        &local_path,
+        relative,
+        &options.copy,
+        options.verify,
+        recorded.as_deref(),
+        &mut |bytes| stats.bytes_pulled += bytes,
+    )?;
+    stats.files_pulled += 1;
+    record(manifest, relative, &local_path, &network_path, copied)
+}
+
+fn catch_up_file(
+    options: &SyncOptions,
+    local: &Path,
+    network: &Path,
+    network_relative: &Path,
+    network_metadata: &Metadata,
+    manifest: &mut Manifest,
+    stats: &mut CatchUpStats,
+) -> io::Result<()> {
+    let relative = &options.layout.to_local(network_relative);
+    if !options.filter.allows(relative) {
+        return Ok(());
+    }
+    let local_path = local.join(relative);
+    let network_path = network.join(network_relative);
+    let local_metadata = match fs::metadata(&local_path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let recorded = manifest.entry(relative);
+    let network_changed =
+        recorded.is_none_or(|entry| !entry.matches(Side::Destination, network_metadata));
+    let local_changed = match (&local_metadata, recorded) {
+        (Some(metadata), Some(entry)) => !entry.matches(Side::Source, metadata),
+        // Never recorded: there is no telling which side is newer.
+        (Some(_), None) => return Ok(()),
+        // Gone locally: deleted on purpose unless the network copy changed since.
+        (None, _) => false,
+    };
+
+    let copied = match (local_changed, network_changed) {
+        (false, true) => {
+            info!(target: PROGRESS,
+                "{} changed on the network, copying to local",
+                relative.display()
+            );
+            let copied = copy::copy_from_network(
+                &network_path,
+                &local_path,
+                relative,
+                &options.copy,
+                options.verify,
+                recorded.map(ManifestEntry::stored_transforms),
+                &mut |_| {},
+            )?;
+            stats.files_pulled += 1;
+            copied
+        }
+        (true, false) => {
+            info!(target: PROGRESS,
+                "{} changed locally, copying to the network",
+                relative.display()
+            );
+            let copied = copy::copy_to_network(
+                &local_path,
+                &network_path,
+                relative,
+                &options.copy,
+                options.verify,
+                &mut |_| {},
+            )?;
+            stats.files_pushed += 1;
+            copied
+        }
+        (true, true) => {
+            warn!(target: EVENTS,
+                "{} changed both locally and on the network, leaving both alone",
+                relative.display()
+            );
+            stats.conflicts += 1;
+            return Ok(());
+        }
+        (false, false) => return Ok(()),
+    };
+    record(manifest, relative, &local_path, &network_path, copied)
+}
+
+/// Records a file just copied either way as in sync.
+fn record(
+    manifest: &mut Manifest,
+    relative: &Path,
+    local_path: &Path,
+    network_path: &Path,
+    copied: Copied,
+) -> io::Result<()> {
+    let local_metadata = fs::metadata(local_path)?;
+    manifest.record(
+        relative,
+        ManifestEntry {
+            size: local_metadata.len(),
+            source_mtime_ns: manifest::mtime_ns(&local_metadata),
+            destination_mtime_ns: manifest::mtime_ns(&fs::metadata(network_path)?),
+            xxh3: Some(copied.hashes.xxh3),
+            sha256: copied.hashes.sha256,
+            stored: copied.stored,
+        },
+    );
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use crate::hash::{self, ContentHashes};
+use crate::manifest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// Bump whenever the on-disk layout changes. A cache written with any other version is discarded
+/// and rebuilt rather than misread.
+pub const CACHE_VERSION: u32 = 1;
+
+/// File name of the cache in the state directory.
+pub const CACHE_FILE: &str = "checksums.json";
+
+/// Hashes of files by device and inode, for `--checksum-cache`. Unlike the manifest, which only
+/// remembers files that were synced, it remembers every file that was hashed, on either side,
+/// and follows a file that was renamed or moved within its filesystem.
+pub struct ChecksumCache {
+    path: PathBuf,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    entries: HashMap<(u64, u64), CachedHash>,
+    changed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: Vec<CachedHash>,
+}
+
+/// A file's hashes, valid as long as its device, inode, size and mtime are all unchanged.
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedHash {
+    device: u64,
+    inode: u64,
+    size: u64,
+    mtime_ns: u64,
+    xxh3: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+/// Device and inode numbers only exist on Unix, so `--checksum-cache` is ignored elsewhere.
+pub fn check_supported(checksum_cache: bool) {
+    if cfg!(not(unix)) && checksum_cache {
+        warn!("--checksum-cache is only supported on Unix, ignoring");
+    }
+}
+
+/// Hashes the file at `path`, through `cache` if there is one.
+pub fn hash_file(
+    cache: Option<&ChecksumCache>,
+    path: &Path,
+    metadata: &Metadata,
+    strong: bool,
+) -> io::Result<ContentHashes> {
+    match cache {
+        Some(cache) => cache.hash_file(path, metadata, strong),
+        None => hash::hash_file(path, strong),
+    }
+}
+
+impl ChecksumCache {
+    /// Loads the cache in `state_dir`, starting empty if there is none or it cannot be read.
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join(CACHE_FILE);
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<CacheFile>(&contents) {
+                Ok(file) if file.version == CACHE_VERSION => file.entries,
+                Ok(file) => {
+                    info!(
+                        "checksum cache {} has version {}, expected {}, rebuilding",
+                        path.display(),
+                        file.version,
+                        CACHE_VERSION
+                    );
+                    Vec::new()
+                }
+                Err(e) => {
+                    warn!("could not parse checksum cache {}: {:?}", path.display(), e);
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("could not read checksum cache {}: {:?}", path.display(), e);
+                Vec::new()
+            }
+        };
+        ChecksumCache {
+            path,
+            state: Mutex::new(CacheState {
+                entries: entries
+                    .into_iter()
+                    .map(|entry| ((entry.device, entry.inode), entry))
+                    .collect(),
+                changed: false,
+            }),
+        }
+    }
+
+    /// Writes the cache back if anything was hashed since it was loaded. Failures are only
+    /// logged, the next run just hashes more.
+    pub fn save(&self) {
+        if let Err(e) = self.write() {
+            error!(
+                "could not save checksum cache {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Writes through a temporary file, so that an interrupted save leaves the previous cache
+    /// intact.
+    fn write(&self) -> io::Result<()> {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !state.changed {
+            return Ok(());
+        }
+        let mut entries: Vec<CachedHash> = state.entries.values().cloned().collect();
+        entries.sort_by_key(|entry| (entry.device, entry.inode));
+        let file = CacheFile {
+            version: CACHE_VERSION,
+            entries,
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary = self.path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec(&file)?)?;
+        fs::rename(&temporary, &self.path)?;
+        state.changed = false;
+        Ok(())
+    }
+
+    /// The hashes of the file at `path`, described by `metadata`, from the cache if the file is
+    /// unchanged since it was last hashed, otherwise read and remembered.
+    pub fn hash_file(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        strong: bool,
+    ) -> io::Result<ContentHashes> {
+        let Some(key) = file_key(metadata) else {
+            return hash::hash_file(path, strong);
+        };
+        let (size, mtime_ns) = (metadata.len(), manifest::mtime_ns(metadata));
+
+        let cached = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entries
+            .get(&key)
+            .filter(|entry| entry.size == size && entry.mtime_ns == mtime_ns)
+            .filter(|entry| !strong || entry.sha256.is_some())
+            .map(|entry| ContentHashes {
+                xxh3: entry.xxh3,
+                sha256: entry.sha256.clone(),
+            });
+        if let Some(hashes) = cached {
+            return Ok(hashes);
+        }
+
+        let hashes = hash::hash_file(path, strong)?;
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.entries.insert(
+            key,
+            CachedHash {
+                device: key.0,
+                inode: key.1,
+                size,
+                mtime_ns,
+                xxh3: hashes.xxh3,
+                sha256: hashes.sha256.clone(),
+            },
+        );
+        state.changed = true;
+        Ok(hashes)
+    }
+}
+
+#[cfg(unix)]
+fn file_key(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_key(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
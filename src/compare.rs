@@ -0,0 +1,156 @@
+use crate::checksum_cache::{self, ChecksumCache};
+use crate::hash::{self, ContentHashes};
+use crate::manifest::{self, Manifest, ManifestEntry, Side};
+use clap::ValueEnum;
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+
+/// How two copies of a file are judged to be the same.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareBy {
+    /// Any existing copy counts as the same, nothing already there is ever rewritten
+    Existence,
+    /// Same size, and the copy not older than the original
+    SizeMtime,
+    /// Same content, by xxHash (and SHA-256 with --verify=all)
+    Checksum,
+}
+
+/// What `--verify` confirms with SHA-256.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verify {
+    /// Only the files copied this run, read back once written
+    Changed,
+    /// The files copied, and every file found up to date, by content (implies --compare-by
+    /// checksum)
+    All,
+}
+
+/// Where a comparison can reuse and record hashes.
+#[derive(Default)]
+pub struct HashCache<'a> {
+    /// The manifest, and the file's path relative to the emulation root. Only meaningful when the
+    /// source is the local side.
+    pub manifest: Option<(&'a Path, &'a mut Manifest)>,
+    /// `--checksum-cache`, for either side.
+    pub checksums: Option<&'a ChecksumCache>,
+}
+
+/// Whether `destination` already holds what `source` does, according to `strategy`. Checksums
+/// come from and, when equal, go to the caches there are. With `verify`, both sides are read from
+/// disk and checksum agreement is confirmed by SHA-256, so a cached hash cannot hide corruption.
+pub fn files_equal(
+    strategy: CompareBy,
+    source: &Path,
+    source_metadata: &Metadata,
+    destination: &Path,
+    destination_metadata: &Metadata,
+    verify: bool,
+    cache: HashCache,
+) -> io::Result<bool> {
+    match strategy {
+        CompareBy::Existence => return Ok(true),
+        CompareBy::SizeMtime => {
+            return Ok(source_metadata.len() == destination_metadata.len()
+                && manifest::mtime_ns(destination_metadata) >= manifest::mtime_ns(source_metadata))
+        }
+        CompareBy::Checksum => {}
+    }
+
+    if source_metadata.len() != destination_metadata.len() {
+        return Ok(false);
+    }
+
+    if verify {
+        let source_hashes = hash::hash_file(source, true)?;
+        let destination_hashes = hash::hash_file(destination, true)?;
+        if source_hashes != destination_hashes {
+            return Ok(false);
+        }
+        record(
+            cache.manifest,
+            source_metadata,
+            destination_metadata,
+            source_hashes,
+        );
+        return Ok(true);
+    }
+
+    let checksums = cache.checksums;
+    let (source_cached, destination_cached) = match &cache.manifest {
+        Some((relative, manifest)) => (
+            manifest
+                .cached(relative, Side::Source, source_metadata)
+                .cloned(),
+            manifest
+                .cached(relative, Side::Destination, destination_metadata)
+                .cloned(),
+        ),
+        None => (None, None),
+    };
+
+    let source_xxh3 = cached_or_hash(
+        source_cached.as_ref().and_then(|e| e.xxh3),
+        source,
+        source_metadata,
+        checksums,
+    )?;
+    let destination_xxh3 = cached_or_hash(
+        destination_cached.as_ref().and_then(|e| e.xxh3),
+        destination,
+        destination_metadata,
+        checksums,
+    )?;
+    if source_xxh3 != destination_xxh3 {
+        return Ok(false);
+    }
+
+    let sha256 = source_cached
+        .filter(|_| destination_cached.is_some())
+        .and_then(|entry| entry.sha256);
+    record(
+        cache.manifest,
+        source_metadata,
+        destination_metadata,
+        ContentHashes {
+            xxh3: source_xxh3,
+            sha256,
+        },
+    );
+    Ok(true)
+}
+
+/// Records a pair found equal in the manifest, if there is one.
+fn record(
+    manifest: Option<(&Path, &mut Manifest)>,
+    source_metadata: &Metadata,
+    destination_metadata: &Metadata,
+    hashes: ContentHashes,
+) {
+    if let Some((relative, manifest)) = manifest {
+        manifest.record(
+            relative,
+            ManifestEntry {
+                size: source_metadata.len(),
+                source_mtime_ns: manifest::mtime_ns(source_metadata),
+                destination_mtime_ns: manifest::mtime_ns(destination_metadata),
+                xxh3: Some(hashes.xxh3),
+                sha256: hashes.sha256,
+                stored: None,
+            },
+        );
+    }
+}
+
+fn cached_or_hash(
+    cached: Option<u64>,
+    path: &Path,
+    metadata: &Metadata,
+    checksums: Option<&ChecksumCache>,
+) -> io::Result<u64> {
+    match cached {
+        Some(xxh3) => Ok(xxh3),
+        None => Ok(checksum_cache::hash_file(checksums, path, metadata, false)?.xxh3),
+    }
+}
@@ -0,0 +1,199 @@
+use crate::bandwidth::RateWindow;
+use crate::retroarch::{self, PathRewriter, RetroArchConfig};
+use crate::{crypt, sqlite};
+use glob::Pattern;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Sync order of top-level folders nobody configured.
+pub const DEFAULT_FOLDER_ORDER: i64 = 100;
+
+/// Top-level EmuDeck folders with a built-in sync order, so that saves and states reach the
+/// network before the long ROM copy starts.
+const BUILT_IN_FOLDER_ORDER: [(&str, i64); 4] =
+    [("saves", 0), ("states", 10), ("bios", 50), ("roms", 200)];
+
+/// Folders `--prioritize-recent-saves` looks in when the config file names none.
+const DEFAULT_SAVE_FOLDERS: [&str; 2] = ["saves", "states"];
+
+/// The transforms the config file's `transforms` list can name.
+const BUILT_IN_TRANSFORMS: [&str; 2] = [crypt::NAME, retroarch::NAME];
+
+/// Settings read from the TOML config file. Everything is optional, but a key that is not one of
+/// these is refused rather than ignored, so a misspelt setting does not silently do nothing.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Sync order of top-level folders, lower numbers are copied first. Merged over the built-in
+    /// order, folders in neither get `DEFAULT_FOLDER_ORDER`.
+    #[serde(default)]
+    pub folder_order: BTreeMap<String, i64>,
+    /// Folders, relative to the emulation root, whose files `--prioritize-recent-saves` considers.
+    #[serde(default)]
+    pub save_folders: Vec<String>,
+    /// Time-of-day bandwidth windows, as for `--bwlimit-schedule`.
+    #[serde(default)]
+    pub bwlimit_schedule: Vec<RateWindow>,
+    /// Glob patterns, relative to the emulation root, of the files `--encrypt` covers.
+    #[serde(default, deserialize_with = "glob_list")]
+    pub encrypt: Vec<String>,
+    /// Glob patterns, relative to the emulation root, of the SQLite databases `--sqlite-safe`
+    /// snapshots.
+    #[serde(default, deserialize_with = "glob_list")]
+    pub sqlite_safe: Vec<String>,
+    /// Names of the transforms files go through on their way to the network, in order.
+    #[serde(default, deserialize_with = "transform_list")]
+    pub transforms: Vec<String>,
+    /// Settings of the `retroarch` transform.
+    #[serde(default)]
+    pub retroarch: RetroArchConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl Config {
+    /// Loads `explicit` if given, otherwise the default config file if there is one.
+    pub fn load(explicit: Option<&Path>) -> Result<Config, ConfigError> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => match default_config_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        let config: Config =
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+        config
+            .check_transforms()
+            .map_err(|e| ConfigError::Invalid(path, e))?;
+        Ok(config)
+    }
+
+    /// What the transforms the config file lists need from the rest of it, which the parser,
+    /// seeing one key at a time, cannot check.
+    fn check_transforms(&self) -> Result<(), String> {
+        if self.transforms.iter().any(|name| name == retroarch::NAME) {
+            PathRewriter::new(&self.retroarch)?;
+        }
+        Ok(())
+    }
+
+    /// The config as a run uses it: the built-in folder order merged in and the defaults of the
+    /// lists left out filled in.
+    pub fn effective(&self) -> Config {
+        Config {
+            folder_order: self.folder_order(),
+            save_folders: self.save_folders(),
+            bwlimit_schedule: self.bwlimit_schedule.clone(),
+            encrypt: or_defaults(&self.encrypt, &crypt::DEFAULT_PATTERNS),
+            sqlite_safe: or_defaults(&self.sqlite_safe, &sqlite::DEFAULT_PATTERNS),
+            transforms: self.transforms.clone(),
+            retroarch: RetroArchConfig {
+                files: or_defaults(&self.retroarch.files, &retroarch::DEFAULT_FILES),
+                ..self.retroarch.clone()
+            },
+        }
+    }
+
+    /// The configured save folders, or saves and states.
+    pub fn save_folders(&self) -> Vec<String> {
+        if self.save_folders.is_empty() {
+            DEFAULT_SAVE_FOLDERS.map(String::from).to_vec()
+        } else {
+            self.save_folders.clone()
+        }
+    }
+
+    /// The configured folder order merged over the built-in one.
+    pub fn folder_order(&self) -> BTreeMap<String, i64> {
+        let mut order: BTreeMap<String, i64> = BUILT_IN_FOLDER_ORDER
+            .iter()
+            .map(|(folder, order)| (folder.to_string(), *order))
+            .collect();
+        order.extend(self.folder_order.clone());
+        order
+    }
+}
+
+/// Parses a config file list of glob patterns, relative to the emulation root, falling back to
+/// `defaults` if it is empty. `key` names the list in errors.
+pub fn parse_globs(
+    patterns: &[String],
+    defaults: &[&str],
+    key: &str,
+) -> Result<Vec<Pattern>, String> {
+    or_defaults(patterns, defaults)
+        .into_iter()
+        .map(|pattern| {
+            Pattern::new(&pattern).map_err(|e| format!("invalid {key} pattern {pattern}: {e}"))
+        })
+        .collect()
+}
+
+fn or_defaults(patterns: &[String], defaults: &[&str]) -> Vec<String> {
+    if patterns.is_empty() {
+        defaults.iter().map(|pattern| pattern.to_string()).collect()
+    } else {
+        patterns.to_vec()
+    }
+}
+
+/// Reads a list of glob patterns, refusing one that does not parse so that the error points at
+/// the line it is on.
+pub fn glob_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    for pattern in &patterns {
+        Pattern::new(pattern)
+            .map_err(|e| de::Error::custom(format!("invalid pattern {pattern}: {e}")))?;
+    }
+    Ok(patterns)
+}
+
+/// Reads the `transforms` list, refusing names that are not built in or are listed twice.
+fn transform_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    for (index, name) in names.iter().enumerate() {
+        if names[..index].contains(name) {
+            return Err(de::Error::custom(format!(
+                "transform {name} is listed twice"
+            )));
+        }
+        if !BUILT_IN_TRANSFORMS.contains(&name.as_str()) {
+            return Err(de::Error::custom(format!(
+                "unknown transform {name}, the built-in ones are: {}",
+                BUILT_IN_TRANSFORMS.join(", ")
+            )));
+        }
+    }
+    Ok(names)
+}
+
+/// `$XDG_CONFIG_HOME/emudeck_sync/config.toml`, falling back to `~/.config`.
+fn default_config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|config_home| config_home.join("emudeck_sync").join("config.toml"))
+}
@@ -0,0 +1,636 @@
+use crate::bandwidth::RateLimiter;
+use crate::hash::{self, ContentHasher, ContentHashes};
+use crate::manifest::StoredCopy;
+use crate::paths;
+use crate::sqlite::{self, Snapshot, SqliteSafe};
+use crate::transform::Pipeline;
+use crate::units::format_bytes;
+use crate::xattrs;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+#[cfg(target_os = "linux")]
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// How individual files and directories are written, shared by the initial sync and the watcher.
+#[derive(Clone, Default)]
+pub struct CopyOptions {
+    /// Mode for every directory we create, instead of whatever the umask leaves.
+    pub dir_mode: Option<u32>,
+    /// Mode for every file we write, instead of the source file's mode.
+    pub file_mode: Option<u32>,
+    /// Let an empty file replace a non-empty one. Off by default, as an emulator that is still
+    /// creating a save leaves it empty for a moment and copying that would destroy the backup.
+    pub allow_shrink: bool,
+    /// Shared by every copy, so `--max-rate` and `--bwlimit-schedule` bound the total.
+    pub rate_limit: Option<Arc<Mutex<RateLimiter>>>,
+    /// The transforms files go through between the two sides, such as `--encrypt`.
+    pub transforms: Pipeline,
+    /// Set by `--sqlite-safe`, for the databases its patterns cover.
+    pub sqlite_safe: Option<Arc<SqliteSafe>>,
+    /// Copy extended attributes along with the content.
+    pub xattrs: bool,
+    /// With `xattrs`, copy POSIX ACLs too.
+    pub acls: bool,
+}
+
+impl CopyOptions {
+    /// Whether the network copy of `relative` is stored differently from the local file, by a
+    /// transform or as a database snapshot, so that only the manifest can tell it is current.
+    pub fn stores_differently(&self, relative: &Path) -> bool {
+        self.transforms.covers(relative)
+            || self
+                .sqlite_safe
+                .as_ref()
+                .is_some_and(|sqlite_safe| sqlite_safe.covers(relative))
+    }
+}
+
+/// What a copy to or from the network wrote: the hashes of the local side's content, and what
+/// the transforms stored on the network if any covered the file.
+pub struct Copied {
+    pub hashes: ContentHashes,
+    pub stored: Option<StoredCopy>,
+    pub method: TransferMethod,
+}
+
+/// How a file's content got to the other side, for the end-of-run breakdown.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum TransferMethod {
+    /// Read and written in full.
+    Full,
+    /// Only the data regions written, the holes left as holes.
+    Sparse,
+    /// Run through the transform pipeline on the way to the network.
+    Transformed,
+    /// Run back through the transform pipeline on the way from the network.
+    Restored,
+    /// Copied from a consistent snapshot of a SQLite database.
+    SqliteSnapshot,
+}
+
+impl fmt::Display for TransferMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TransferMethod::Full => "fully copied",
+            TransferMethod::Sparse => "sparse copied",
+            TransferMethod::Transformed => "transformed",
+            TransferMethod::Restored => "restored",
+            TransferMethod::SqliteSnapshot => "snapshotted databases",
+        })
+    }
+}
+
+/// Parses an octal permission mode such as `755`, `0755` or `0o755`.
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{value} is not an octal permission mode")),
+    }
+}
+
+/// Warns once at startup if modes were requested on a platform that cannot apply them.
+pub fn check_modes_supported(options: &CopyOptions) {
+    if cfg!(not(unix)) && (options.dir_mode.is_some() || options.file_mode.is_some()) {
+        warn!("--dir-mode and --file-mode are only supported on Unix, ignoring");
+    }
+}
+
+/// Like `fs::create_dir_all`, but applies `--dir-mode` to each directory it actually creates.
+pub fn create_dir_all(path: &Path, options: &CopyOptions) -> io::Result<()> {
+    // A relative path's last parent is empty, which is the working directory.
+    if path.as_os_str().is_empty() || path.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent, options)?;
+    }
+
+    match fs::create_dir(path) {
+        Ok(()) => {}
+        // Lost a race with another creator, which is just as good.
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    if let Some(mode) = options.dir_mode {
+        set_mode(path, mode)?;
+    }
+    Ok(())
+}
+
+/// Copies a local file to the network, through the transforms that cover `relative`. A database
+/// `--sqlite-safe` covers is copied from a snapshot of it.
+pub fn copy_to_network(
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    strong: bool,
+    progress: &mut impl FnMut(u64),
+) -> io::Result<Copied> {
+    let snapshot = match &options.sqlite_safe {
+        Some(sqlite_safe) if sqlite_safe.covers(relative) => match sqlite::snapshot(source) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("{}, copying it as it is", e);
+                None
+            }
+            Err(e) => return Err(e),
+        },
+        _ => None,
+    };
+    // Progress counts the local file's bytes, which a snapshot has more or fewer of.
+    let lengths = match &snapshot {
+        Some(snapshot) => Some((
+            fs::metadata(source)?.len(),
+            fs::metadata(snapshot.path())?.len(),
+        )),
+        None => None,
+    };
+    if let (Some(snapshot), true) = (&snapshot, options.xattrs) {
+        xattrs::copy_attributes(source, snapshot.path(), options.acls);
+    }
+    let source = snapshot.as_ref().map_or(source, Snapshot::path);
+
+    let mut read = 0u64;
+    let mut reported = 0u64;
+    let mut progress = |bytes: u64| {
+        read += bytes;
+        let local = match lengths {
+            Some((local_len, snapshot_len)) => {
+                (u128::from(read) * u128::from(local_len) / u128::from(snapshot_len.max(1))) as u64
+            }
+            None => read,
+        };
+        progress(local - reported);
+        reported = local;
+    };
+    let mut copied = if options.transforms.covers(relative) {
+        copy_transformed(
+            source,
+            destination,
+            relative,
+            options,
+            strong,
+            &mut progress,
+            Direction::ToNetwork,
+        )?
+    } else {
+        copy_file(source, destination, options, strong, &mut progress)?
+    };
+    if let Some((_, snapshot_len)) = lengths {
+        copied.stored.get_or_insert(StoredCopy {
+            transforms: Vec::new(),
+            file_name: None,
+            size: snapshot_len,
+        });
+        copied.method = TransferMethod::SqliteSnapshot;
+    }
+    Ok(copied)
+}
+
+/// Copies a network file to the local side, undoing the transforms that cover `relative`.
+/// `recorded` is as `Pipeline::reverse` takes it.
+pub fn copy_from_network(
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    strong: bool,
+    recorded: Option<&[String]>,
+    progress: &mut impl FnMut(u64),
+) -> io::Result<Copied> {
+    if options.transforms.covers(relative) {
+        copy_transformed(
+            source,
+            destination,
+            relative,
+            options,
+            strong,
+            progress,
+            Direction::FromNetwork { recorded },
+        )
+    } else {
+        copy_file(source, destination, options, strong, progress)
+    }
+}
+
+/// The hashes of what a network file holds once the transforms covering `relative` are undone,
+/// with `recorded` as `Pipeline::reverse` takes it.
+pub fn hash_network_file(
+    path: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    strong: bool,
+    recorded: Option<&[String]>,
+) -> io::Result<ContentHashes> {
+    if !options.transforms.covers(relative) {
+        return hash::hash_file(path, strong);
+    }
+    let mut reader = options
+        .transforms
+        .reverse(relative, Box::new(File::open(path)?), recorded)?
+        .reader;
+    let mut hasher = ContentHasher::new(strong);
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// Which way `copy_transformed` runs the pipeline.
+enum Direction<'a> {
+    ToNetwork,
+    /// `recorded` as `Pipeline::reverse` takes it.
+    FromNetwork {
+        recorded: Option<&'a [String]>,
+    },
+}
+
+/// Copies `source` over `destination` through the transform pipeline, in `direction`. What is
+/// hashed, throttled and reported is the local side's content: what is read on the way to the
+/// network, what is written on the way back. A transform that renames the file changes the
+/// destination's file name.
+fn copy_transformed(
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    strong: bool,
+    progress: &mut impl FnMut(u64),
+    direction: Direction,
+) -> io::Result<Copied> {
+    let to_network = matches!(direction, Direction::ToNetwork);
+    let source_metadata = fs::metadata(source)?;
+    check_copyable(&source_metadata)?;
+    let hasher = Rc::new(RefCell::new(ContentHasher::new(strong)));
+    let read = Rc::new(Cell::new(0));
+    let reader = MeteredReader {
+        inner: File::open(source)?,
+        hasher: to_network.then(|| Rc::clone(&hasher)),
+        read: Rc::clone(&read),
+    };
+    let transformed = match direction {
+        Direction::ToNetwork => options.transforms.apply(relative, Box::new(reader))?,
+        Direction::FromNetwork { recorded } => {
+            options
+                .transforms
+                .reverse(relative, Box::new(reader), recorded)?
+        }
+    };
+
+    let renamed = match transformed.path.file_name() {
+        Some(name) if transformed.path != relative => Some(name.to_os_string()),
+        _ => None,
+    };
+    let destination = match &renamed {
+        Some(name) => destination.with_file_name(name),
+        None => destination.to_path_buf(),
+    };
+    prepare_destination(&destination, source_metadata.len(), options)?;
+
+    let mut reader = transformed.reader;
+    let mut writer = File::create(&destination)?;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut written = 0;
+    let mut reported = 0;
+    loop {
+        let chunk = reader.read(&mut buffer)?;
+        if !to_network {
+            hasher.borrow_mut().update(&buffer[..chunk]);
+        }
+        writer.write_all(&buffer[..chunk])?;
+        written += chunk as u64;
+
+        let local = if to_network { read.get() } else { written };
+        if local > reported {
+            throttle(options, local - reported);
+            progress(local - reported);
+            reported = local;
+        }
+        if chunk == 0 {
+            break;
+        }
+    }
+    writer.flush()?;
+    drop(reader);
+    finish_destination(source, &destination, &source_metadata, options)?;
+
+    let (size, file_name) = if to_network {
+        (written, renamed)
+    } else {
+        (
+            source_metadata.len(),
+            renamed.and(source.file_name().map(|name| name.to_os_string())),
+        )
+    };
+    Ok(Copied {
+        hashes: hasher.replace(ContentHasher::new(false)).finish(),
+        stored: Some(StoredCopy {
+            transforms: transformed.applied,
+            file_name: file_name.map(|name| paths::escape(name.as_encoded_bytes())),
+            size,
+        }),
+        method: if to_network {
+            TransferMethod::Transformed
+        } else {
+            TransferMethod::Restored
+        },
+    })
+}
+
+/// Counts what the transform pipeline reads from the source, hashing it too on the way to the
+/// network.
+struct MeteredReader {
+    inner: File,
+    hasher: Option<Rc<RefCell<ContentHasher>>>,
+    read: Rc<Cell<u64>>,
+}
+
+impl Read for MeteredReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        if let Some(hasher) = &self.hasher {
+            hasher.borrow_mut().update(&buffer[..read]);
+        }
+        self.read.set(self.read.get() + read as u64);
+        Ok(read)
+    }
+}
+
+/// Copies `source` over `destination`, hashing the content on the way through and reporting each
+/// chunk written to `progress`. Sparse files stay sparse where the platform can tell where their
+/// holes are.
+fn copy_file(
+    source: &Path,
+    destination: &Path,
+    options: &CopyOptions,
+    strong: bool,
+    progress: &mut impl FnMut(u64),
+) -> io::Result<Copied> {
+    let source_metadata = fs::metadata(source)?;
+    check_copyable(&source_metadata)?;
+    let mut reader = File::open(source)?;
+    prepare_destination(destination, source_metadata.len(), options)?;
+
+    let mut writer = File::create(destination)?;
+    let mut hasher = ContentHasher::new(strong);
+    let sparse = is_sparse(&source_metadata);
+    if sparse {
+        copy_sparse(
+            &mut reader,
+            &mut writer,
+            source_metadata.len(),
+            &mut hasher,
+            options,
+            progress,
+        )?;
+    } else {
+        copy_dense(&mut reader, &mut writer, &mut hasher, &mut |bytes| {
+            throttle(options, bytes);
+            progress(bytes)
+        })?;
+    }
+    writer.flush()?;
+    finish_destination(source, destination, &source_metadata, options)?;
+
+    Ok(Copied {
+        hashes: hasher.finish(),
+        stored: None,
+        method: if sparse {
+            TransferMethod::Sparse
+        } else {
+            TransferMethod::Full
+        },
+    })
+}
+
+/// Refuses anything but a regular file. FIFOs, sockets and device nodes have no content to back
+/// up, and opening a FIFO would block until something writes to it.
+pub fn check_copyable(metadata: &fs::Metadata) -> io::Result<()> {
+    if metadata.is_file() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("unsupported file type, {}", describe_file_type(metadata)),
+    ))
+}
+
+#[cfg(unix)]
+fn describe_file_type(metadata: &fs::Metadata) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        "a FIFO"
+    } else if file_type.is_socket() {
+        "a socket"
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        "a device node"
+    } else {
+        "not a regular file"
+    }
+}
+
+#[cfg(not(unix))]
+fn describe_file_type(_metadata: &fs::Metadata) -> &'static str {
+    "not a regular file"
+}
+
+/// Creates the destination's parent, and refuses to replace a non-empty file with `len` zero
+/// bytes unless `--allow-shrink`.
+pub fn prepare_destination(destination: &Path, len: u64, options: &CopyOptions) -> io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        create_dir_all(parent, options)?;
+    }
+
+    if len == 0 && !options.allow_shrink {
+        if let Ok(existing) = fs::metadata(destination) {
+            if existing.len() > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "not replacing {} ({}) with an empty file, --allow-shrink allows it",
+                        destination.display(),
+                        format_bytes(existing.len())
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies `--file-mode`, or otherwise the source's mode, to a file just written, then with
+/// `--xattrs` the source's extended attributes, which an ACL among them has to come after.
+fn finish_destination(
+    source: &Path,
+    destination: &Path,
+    source_metadata: &fs::Metadata,
+    options: &CopyOptions,
+) -> io::Result<()> {
+    match options.file_mode {
+        Some(mode) => set_mode(destination, mode)?,
+        None => fs::set_permissions(destination, source_metadata.permissions())?,
+    }
+    if options.xattrs {
+        xattrs::copy_attributes(source, destination, options.acls);
+    }
+    Ok(())
+}
+
+/// Waits as long as the bandwidth limit needs for `bytes` just written.
+pub fn throttle(options: &CopyOptions, bytes: u64) {
+    if let Some(limiter) = &options.rate_limit {
+        limiter
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .consume(bytes);
+    }
+}
+
+fn copy_dense(
+    reader: &mut File,
+    writer: &mut File,
+    hasher: &mut ContentHasher,
+    progress: &mut impl FnMut(u64),
+) -> io::Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        progress(read as u64);
+    }
+}
+
+/// A file is sparse if fewer blocks are allocated than its length needs.
+#[cfg(target_os = "linux")]
+fn is_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_sparse(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Copies only the data regions of `reader`, leaving its holes as holes in `writer`. Holes still
+/// count towards the hash as the zeros they read as, so the result matches a dense copy, and
+/// towards `progress`, but not the bandwidth limit, as nothing is read or written for them.
+#[cfg(target_os = "linux")]
+fn copy_sparse(
+    reader: &mut File,
+    writer: &mut File,
+    len: u64,
+    hasher: &mut ContentHasher,
+    options: &CopyOptions,
+    progress: &mut impl FnMut(u64),
+) -> io::Result<()> {
+    let zeros = vec![0; BUFFER_SIZE];
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut position = 0;
+    while position < len {
+        let data = seek_region(reader, position, libc::SEEK_DATA)?
+            .unwrap_or(len)
+            .min(len);
+        let mut hole_left = data - position;
+        while hole_left > 0 {
+            let chunk = hole_left.min(BUFFER_SIZE as u64) as usize;
+            hasher.update(&zeros[..chunk]);
+            progress(chunk as u64);
+            hole_left -= chunk as u64;
+        }
+        if data == len {
+            break;
+        }
+
+        let hole = seek_region(reader, data, libc::SEEK_HOLE)?
+            .unwrap_or(len)
+            .min(len);
+        reader.seek(SeekFrom::Start(data))?;
+        writer.seek(SeekFrom::Start(data))?;
+        let mut data_left = hole - data;
+        while data_left > 0 {
+            let chunk = data_left.min(BUFFER_SIZE as u64) as usize;
+            let read = reader.read(&mut buffer[..chunk])?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            writer.write_all(&buffer[..read])?;
+            throttle(options, read as u64);
+            progress(read as u64);
+            data_left -= read as u64;
+        }
+        position = hole;
+    }
+
+    // A trailing hole is never written, so extend the file to its full length.
+    writer.set_len(len)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_sparse(
+    reader: &mut File,
+    writer: &mut File,
+    _len: u64,
+    hasher: &mut ContentHasher,
+    options: &CopyOptions,
+    progress: &mut impl FnMut(u64),
+) -> io::Result<()> {
+    copy_dense(reader, writer, hasher, &mut |bytes| {
+        throttle(options, bytes);
+        progress(bytes)
+    })
+}
+
+/// The offset of the next data region (`SEEK_DATA`) or hole (`SEEK_HOLE`) at or after `offset`,
+/// or `None` if there is none before the end of the file.
+#[cfg(target_os = "linux")]
+fn seek_region(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: lseek only repositions the descriptor, which `file` keeps open for the call, and
+    // every read and write afterwards seeks explicitly.
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if result >= 0 {
+        return Ok(Some(result as u64));
+    }
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() == Some(libc::ENXIO) {
+        Ok(None)
+    } else {
+        Err(error)
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use crate::config;
+use crate::transform::Transform;
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use glob::Pattern;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Starts every encrypted file, followed by the nonce and then the ciphertext with its tag. The
+/// file's path relative to the emulation root is the associated data, so a file moved or swapped
+/// with another on the network no longer decrypts. The nonce is only kept in this header and not
+/// in the manifest: the file carries it wherever it is read, and other devices replace it with
+/// their own whenever they push, so a copy in this device's manifest would say nothing new.
+const MAGIC: &[u8; 8] = b"EDSYNC\x00\x02";
+/// Starts files encrypted before the path was bound to them, which are still read without it.
+const LEGACY_MAGIC: &[u8; 8] = b"EDSYNC\x00\x01";
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN;
+
+/// Holds the key derivation salt in the network emulation directory, so every device that knows
+/// the passphrase derives the same key. Losing it makes the encrypted files unreadable.
+pub const SALT_FILE: &str = ".emudeck_sync-salt";
+const SALT_LEN: usize = 16;
+
+/// The transform's name in the config file's `transforms` list.
+pub const NAME: &str = "encrypt";
+
+/// What `--encrypt` covers when the config file names no patterns.
+pub const DEFAULT_PATTERNS: [&str; 2] = ["saves/**", "states/**"];
+
+/// The `encrypt` transform: encrypts files matching the configured patterns on their way to the
+/// network and decrypts them on the way back, with XChaCha20-Poly1305 under a key derived from a
+/// passphrase by Argon2id. Whole files are held in memory, which suits saves rather than ROMs.
+pub struct Encryption {
+    cipher: XChaCha20Poly1305,
+    patterns: Vec<Pattern>,
+}
+
+impl Encryption {
+    /// Derives the key from `passphrase` and the salt in `network_root`, creating the salt if this
+    /// is the first encrypted run against that directory.
+    pub fn new(passphrase: &[u8], network_root: &Path, patterns: Vec<Pattern>) -> io::Result<Self> {
+        let salt = load_or_create_salt(network_root)?;
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(passphrase, &salt, &mut key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Encryption {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            patterns,
+        })
+    }
+}
+
+impl Transform for Encryption {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    /// Whether the file at `path` relative to the emulation root is kept encrypted on the network.
+    fn covers(&self, path: &Path) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches_path(path))
+    }
+
+    fn apply(
+        &self,
+        path: &Path,
+        mut reader: Box<dyn Read>,
+    ) -> io::Result<(PathBuf, Box<dyn Read>)> {
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: &associated_data(path),
+                },
+            )
+            .map_err(|_| io::Error::other(format!("could not encrypt {}", path.display())))?;
+
+        let mut encrypted = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        encrypted.extend_from_slice(MAGIC);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok((path.to_path_buf(), Box::new(Cursor::new(encrypted))))
+    }
+
+    /// A file without the header is passed through as it is if the manifest records it stored
+    /// before `--encrypt` was turned on. Any other one is refused, as a file that should be
+    /// encrypted but is not may have been replaced on the network.
+    fn reverse(
+        &self,
+        path: &Path,
+        mut reader: Box<dyn Read>,
+        stored_without: bool,
+    ) -> io::Result<(PathBuf, Box<dyn Read>)> {
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+        let legacy = contents.starts_with(LEGACY_MAGIC);
+        if !legacy && !contents.starts_with(MAGIC) {
+            if !stored_without {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} is not encrypted on the network, and the manifest does not record it stored in plaintext",
+                        path.display()
+                    ),
+                ));
+            }
+            info!(
+                "{} is not encrypted on the network, copying it as it is",
+                path.display()
+            );
+            return Ok((path.to_path_buf(), Box::new(Cursor::new(contents))));
+        }
+        if contents.len() < HEADER_LEN + TAG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is truncated", path.display()),
+            ));
+        }
+
+        let nonce = XNonce::from_slice(&contents[MAGIC.len()..HEADER_LEN]);
+        let aad = if legacy {
+            Vec::new()
+        } else {
+            associated_data(path)
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &contents[HEADER_LEN..],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "could not decrypt {}, the passphrase is wrong or the file was tampered with",
+                        path.display()
+                    ),
+                )
+            })?;
+        Ok((path.to_path_buf(), Box::new(Cursor::new(plaintext))))
+    }
+}
+
+/// The path's components joined with `/`, so devices with different separators agree on it.
+fn associated_data(path: &Path) -> Vec<u8> {
+    let mut aad = Vec::new();
+    for component in path.components() {
+        if !aad.is_empty() {
+            aad.push(b'/');
+        }
+        aad.extend_from_slice(component.as_os_str().as_encoded_bytes());
+    }
+    aad
+}
+
+/// Parses `--encrypt` patterns, relative to the emulation root, falling back to
+/// `DEFAULT_PATTERNS`.
+pub fn parse_patterns(patterns: &[String]) -> Result<Vec<Pattern>, String> {
+    config::parse_globs(patterns, &DEFAULT_PATTERNS, "encrypt")
+}
+
+/// Reads `--passphrase-file`, or without one the `EMUDECK_SYNC_PASSPHRASE` environment variable.
+/// A trailing newline in the file is not part of the passphrase.
+pub fn read_passphrase(file: Option<&Path>) -> Result<Vec<u8>, String> {
+    let passphrase = match file {
+        Some(file) => {
+            let mut contents = fs::read(file)
+                .map_err(|e| format!("could not read passphrase {}: {}", file.display(), e))?;
+            while contents
+                .last()
+                .is_some_and(|byte| *byte == b'\n' || *byte == b'\r')
+            {
+                contents.pop();
+            }
+            contents
+        }
+        None => std::env::var_os("EMUDECK_SYNC_PASSPHRASE")
+            .map(|passphrase| passphrase.into_encoded_bytes())
+            .ok_or_else(|| {
+                "--encrypt needs a passphrase, from --passphrase-file or EMUDECK_SYNC_PASSPHRASE"
+                    .to_string()
+            })?,
+    };
+    if passphrase.is_empty() {
+        return Err("the --encrypt passphrase is empty".to_string());
+    }
+    Ok(passphrase)
+}
+
+fn load_or_create_salt(network_root: &Path) -> io::Result<Vec<u8>> {
+    let path = network_root.join(SALT_FILE);
+    match fs::read(&path) {
+        Ok(salt) if salt.len() >= SALT_LEN => return Ok(salt),
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is too short to be a salt", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut salt = vec![0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    fs::create_dir_all(network_root)?;
+    // Another device may create it at the same moment, in which case its salt wins.
+    match File::options().write(true).create_new(true).open(&path) {
+        Ok(mut file) => {
+            file.write_all(&salt)?;
+            warn!(
+                "created {}, encrypted files cannot be read without it",
+                path.display()
+            );
+            Ok(salt)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => fs::read(&path),
+        Err(e) => Err(e),
+    }
+}
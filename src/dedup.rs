@@ -0,0 +1,144 @@
+use crate::hash;
+use crate::manifest::{Manifest, Side};
+use crate::paths;
+use crate::sync;
+use crate::telemetry::ERRORS;
+use crate::units::format_bytes;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Files with identical content, any one of which could stand in for the rest.
+#[derive(Serialize)]
+pub struct DuplicateSet {
+    pub size: u64,
+    /// What deleting all but one copy would free.
+    pub reclaimable_bytes: u64,
+    #[serde(serialize_with = "serialize_paths")]
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Serialize)]
+pub struct DedupReport {
+    pub reclaimable_bytes: u64,
+    /// Largest saving first.
+    pub sets: Vec<DuplicateSet>,
+}
+
+/// Groups the files under `root` by content. Only files sharing a size are hashed, reusing hashes
+/// the manifest already holds for unchanged files, and with `strong` the SHA-256 digest has to
+/// match as well. Unreadable folders are left out with a warning, like a sync does. Nothing is
+/// modified.
+pub fn find_duplicates(root: &Path, manifest: &Manifest, strong: bool) -> io::Result<DedupReport> {
+    let mut by_size: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    paths::walk_files(
+        root,
+        Path::new(""),
+        &[],
+        &mut |relative, metadata| {
+            if metadata.len() > 0 {
+                by_size
+                    .entry(metadata.len())
+                    .or_default()
+                    .push(relative.to_path_buf());
+            }
+            Ok(())
+        },
+        &mut |relative, e| {
+            if !sync::skippable(&e) {
+                return Err(e);
+            }
+            warn!(target: ERRORS, "skipping {}: {}", relative.display(), e);
+            Ok(())
+        },
+    )?;
+
+    let mut sets = Vec::new();
+    for (size, candidates) in by_size {
+        if candidates.len() < 2 {
+            continue;
+        }
+
+        let mut by_content: BTreeMap<(u64, Option<String>), Vec<PathBuf>> = BTreeMap::new();
+        for relative in candidates {
+            match content_key(root, &relative, manifest, strong) {
+                Ok(key) => by_content.entry(key).or_default().push(relative),
+                Err(e) => warn!("could not hash {}: {:?}", relative.display(), e),
+            }
+        }
+
+        sets.extend(
+            by_content
+                .into_values()
+                .filter(|paths| paths.len() > 1)
+                .map(|paths| DuplicateSet {
+                    size,
+                    reclaimable_bytes: size * (paths.len() as u64 - 1),
+                    paths,
+                }),
+        );
+    }
+
+    sets.sort_by(|a, b| {
+        b.reclaimable_bytes
+            .cmp(&a.reclaimable_bytes)
+            .then_with(|| a.paths.cmp(&b.paths))
+    });
+    Ok(DedupReport {
+        reclaimable_bytes: sets.iter().map(|set| set.reclaimable_bytes).sum(),
+        sets,
+    })
+}
+
+/// JSON has no room for paths that are not UTF-8, so those are written lossily.
+fn serialize_paths<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(paths.iter().map(|path| path.to_string_lossy()))
+}
+
+pub fn print_report(report: &DedupReport, json: bool) -> io::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(report)?);
+        return Ok(());
+    }
+
+    println!(
+        "{} duplicate sets, {} reclaimable",
+        report.sets.len(),
+        format_bytes(report.reclaimable_bytes)
+    );
+    for set in &report.sets {
+        println!(
+            "{} reclaimable, {} copies of {}:",
+            format_bytes(set.reclaimable_bytes),
+            set.paths.len(),
+            format_bytes(set.size)
+        );
+        for path in &set.paths {
+            println!("  {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn content_key(
+    root: &Path,
+    relative: &Path,
+    manifest: &Manifest,
+    strong: bool,
+) -> io::Result<(u64, Option<String>)> {
+    let path = root.join(relative);
+    let metadata = fs::metadata(&path)?;
+    if let Some(entry) = manifest.cached(relative, Side::Source, &metadata) {
+        match (entry.xxh3, &entry.sha256) {
+            (Some(xxh3), Some(sha256)) if strong => return Ok((xxh3, Some(sha256.clone()))),
+            (Some(xxh3), _) if !strong => return Ok((xxh3, None)),
+            _ => {}
+        }
+    }
+
+    let hashes = hash::hash_file(&path, strong)?;
+    Ok((hashes.xxh3, hashes.sha256))
+}
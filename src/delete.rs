@@ -0,0 +1,221 @@
+use crate::telemetry::{ERRORS, EVENTS, PROGRESS};
+use crate::units::{self, format_timestamp};
+use std::ffi::OsString;
+use std::fs::{self, Metadata};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+#[derive(Clone, Default)]
+pub struct DeleteOptions {
+    /// Ask on the terminal before deleting anything.
+    pub confirm: bool,
+    /// Move deleted files here instead of removing them, below a folder named for when they were
+    /// deleted. Must be on the same filesystem as what is deleted, as files are renamed into it.
+    pub trash: Option<PathBuf>,
+    /// How long trashed files are kept, zero keeps them forever.
+    pub trash_keep: Duration,
+}
+
+/// Every destructive operation goes through here, so the safety nets apply to all of them alike.
+pub struct Deleter {
+    options: DeleteOptions,
+    /// What trashed paths are made relative to, so the trash mirrors the layout they came from.
+    root: PathBuf,
+}
+
+/// Prompting needs someone to answer, so `--confirm-deletes` is refused when not interactive.
+pub fn check_confirm_supported(options: &DeleteOptions) -> Result<(), String> {
+    if options.confirm && !(io::stdout().is_terminal() && io::stdin().is_terminal()) {
+        return Err("--confirm-deletes needs an interactive terminal to prompt on".to_string());
+    }
+    Ok(())
+}
+
+impl Deleter {
+    pub fn new(options: &DeleteOptions, root: &Path) -> Self {
+        Deleter {
+            options: options.clone(),
+            root: root.to_path_buf(),
+        }
+    }
+
+    /// Deletes `files`, which all live in `folder`, as one batch. With `--confirm-deletes` the
+    /// whole batch is skipped unless confirmed. Returns how many files were deleted.
+    pub fn delete_files(&mut self, folder: &Path, files: &[PathBuf]) -> usize {
+        let summary = format!("{} files in {}", files.len(), folder.display());
+        if files.is_empty() || !self.confirm(&summary, files) {
+            return 0;
+        }
+
+        let mut deleted = 0;
+        for file in files {
+            match self.remove(file, |file| fs::remove_file(file)) {
+                Ok(()) => deleted += 1,
+                Err(e) => error!(target: ERRORS, "could not delete {}: {:?}", file.display(), e),
+            }
+        }
+        deleted
+    }
+
+    /// Deletes the directory `tree` and everything below it. Returns whether it was deleted.
+    pub fn delete_tree(&mut self, tree: &Path) -> bool {
+        let summary = format!("{} and everything in it", tree.display());
+        if !self.confirm(&summary, &[]) {
+            return false;
+        }
+
+        match self.remove(tree, |tree| fs::remove_dir_all(tree)) {
+            Ok(()) => true,
+            Err(e) => {
+                error!(target: ERRORS, "could not delete {}: {:?}", tree.display(), e);
+                false
+            }
+        }
+    }
+
+    /// Moves `path` into the trash if there is one, below a folder named for the time of
+    /// deletion that pruning goes by, otherwise removes it with `remove`.
+    fn remove(&self, path: &Path, remove: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+        let Some(trash) = &self.options.trash else {
+            info!(target: PROGRESS, "deleting {}", path.display());
+            return remove(path);
+        };
+
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => PathBuf::from(path.file_name().unwrap_or_default()),
+        };
+        let mut target = trash
+            .join(format_timestamp(SystemTime::now()))
+            .join(relative);
+        let mut copy = 1;
+        while fs::symlink_metadata(&target).is_ok() {
+            // Trashed before within the same second, keep both rather than losing the older copy.
+            copy += 1;
+            let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+            name.push(format!(".{copy}"));
+            target.set_file_name(name);
+        }
+
+        info!(target: PROGRESS, "moving {} to trash {}", path.display(), target.display());
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(path, &target)
+    }
+
+    fn confirm(&mut self, summary: &str, paths: &[PathBuf]) -> bool {
+        if !self.options.confirm {
+            return true;
+        }
+
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "about to delete {summary}");
+        for path in paths {
+            let _ = writeln!(stdout, "  {}", path.display());
+        }
+        let _ = write!(stdout, "delete? [y/N] ");
+        let _ = stdout.flush();
+
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer).is_err() {
+            return false;
+        }
+        let confirmed = matches!(answer.trim(), "y" | "Y" | "yes");
+        if !confirmed {
+            info!("skipped deleting {summary}");
+        }
+        confirmed
+    }
+}
+
+/// Permanently removes what has been in the trash for longer than `--trash-keep`, going by the
+/// name of the folder it was deleted into, then any directories that left empty.
+pub fn prune_trash(options: &DeleteOptions) {
+    let Some(trash) = &options.trash else {
+        return;
+    };
+    if options.trash_keep.is_zero() || !trash.is_dir() {
+        return;
+    }
+
+    let Some(cutoff) = SystemTime::now().checked_sub(options.trash_keep) else {
+        return;
+    };
+    // Timestamped names sort chronologically.
+    let cutoff_name = format_timestamp(cutoff);
+    let mut pruned = 0;
+    for (path, metadata) in trash_entries(trash) {
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if metadata.is_dir() && units::is_timestamp(name) => {
+                if name < cutoff_name.as_str() {
+                    pruned += prune_entry(&path, &metadata, None);
+                }
+            }
+            // Trashed before deletions were dated.
+            _ => pruned += prune_entry(&path, &metadata, Some(cutoff)),
+        }
+    }
+    if pruned > 0 {
+        info!(target: EVENTS, "pruned {} files from trash {}", pruned, trash.display());
+    }
+}
+
+fn trash_entries(directory: &Path) -> Vec<(PathBuf, Metadata)> {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let metadata = fs::symlink_metadata(&path).ok()?;
+                Some((path, metadata))
+            })
+            .collect(),
+        Err(e) => {
+            warn!("could not read trash {}: {:?}", directory.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// Removes the file or directory tree at `path`, or with a `cutoff` only the files in it trashed
+/// before then. Returns how many files were removed.
+fn prune_entry(path: &Path, metadata: &Metadata, cutoff: Option<SystemTime>) -> usize {
+    if metadata.is_dir() {
+        let pruned = trash_entries(path)
+            .iter()
+            .map(|(path, metadata)| prune_entry(path, metadata, cutoff))
+            .sum();
+        // Only succeeds once everything in it is gone.
+        let _ = fs::remove_dir(path);
+        return pruned;
+    }
+    if cutoff.is_some_and(|cutoff| trashed_at(metadata) >= cutoff) {
+        return 0;
+    }
+    match fs::remove_file(path) {
+        Ok(()) => 1,
+        Err(e) => {
+            error!(target: ERRORS, "could not prune {}: {:?}", path.display(), e);
+            0
+        }
+    }
+}
+
+/// When a file was moved into the trash before deletions were dated. Renaming updates a file's
+/// change time on Unix, though not that of the files in a directory renamed with it; elsewhere
+/// the modification time is the best there is.
+#[cfg(unix)]
+fn trashed_at(metadata: &Metadata) -> SystemTime {
+    use std::os::unix::fs::MetadataExt;
+
+    let seconds = u64::try_from(metadata.ctime()).unwrap_or(0);
+    SystemTime::UNIX_EPOCH + Duration::new(seconds, metadata.ctime_nsec() as u32)
+}
+
+#[cfg(not(unix))]
+fn trashed_at(metadata: &Metadata) -> SystemTime {
+    metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)
+}
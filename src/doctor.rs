@@ -0,0 +1,307 @@
+use crate::lock;
+use crate::manifest::Manifest;
+use crate::network;
+use crate::sync::{self, SyncOptions};
+use crate::units::format_bytes;
+use crate::watch::{self, WatchOptions};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const PROBE_FILE_NAME: &str = ".emudeck_sync_doctor_probe";
+
+/// Warn once the estimated sync would use more than this share of the free space.
+const SPACE_WARNING_RATIO: f64 = 0.9;
+
+/// Warn once the recursive watch would use more than this share of the free inotify watches.
+#[cfg(target_os = "linux")]
+const WATCH_WARNING_RATIO: f64 = 0.8;
+
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Collects check results, printing each as it is made.
+struct Checklist {
+    failed: bool,
+}
+
+impl Checklist {
+    fn report(&mut self, status: Status, message: String) {
+        let label = match status {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => {
+                self.failed = true;
+                "FAIL"
+            }
+        };
+        println!("[{label}] {message}");
+    }
+}
+
+/// Checks that the directories, mount, permissions and surrounding system are fit for syncing,
+/// printing a checklist. Returns `false` if any critical check failed.
+pub fn run(
+    local_root: &Path,
+    network_root: &Path,
+    state_dir: &Path,
+    sync_options: &SyncOptions,
+    watch_options: &WatchOptions,
+) -> bool {
+    let mut checklist = Checklist { failed: false };
+
+    let local_readable = check_local_root(&mut checklist, local_root);
+    let network_writable = check_network_root(&mut checklist, network_root);
+    if local_readable && network_writable {
+        check_free_space(
+            &mut checklist,
+            local_root,
+            network_root,
+            state_dir,
+            sync_options,
+        );
+    }
+    if network_writable {
+        check_watch_limit(&mut checklist, watch_options);
+    }
+    check_lock(&mut checklist, state_dir);
+
+    !checklist.failed
+}
+
+fn check_local_root(checklist: &mut Checklist, local_root: &Path) -> bool {
+    match fs::read_dir(local_root) {
+        Ok(_) => {
+            checklist.report(
+                Status::Pass,
+                format!(
+                    "local emulation directory {} is readable",
+                    local_root.display()
+                ),
+            );
+            true
+        }
+        Err(e) => {
+            checklist.report(
+                Status::Fail,
+                format!(
+                    "local emulation directory {} is not readable: {}",
+                    local_root.display(),
+                    e
+                ),
+            );
+            false
+        }
+    }
+}
+
+fn check_network_root(checklist: &mut Checklist, network_root: &Path) -> bool {
+    if !network_root.is_dir() {
+        checklist.report(
+            Status::Fail,
+            format!(
+                "network emulation directory {} does not exist or is not a directory",
+                network_root.display()
+            ),
+        );
+        return false;
+    }
+
+    if network::is_mount_point(network_root) {
+        checklist.report(
+            Status::Pass,
+            format!(
+                "network emulation directory {} is a mount point",
+                network_root.display()
+            ),
+        );
+    } else {
+        checklist.report(
+            Status::Warn,
+            format!(
+                "network emulation directory {} is not a mount point, if the share is not mounted files land on the local disk",
+                network_root.display()
+            ),
+        );
+    }
+
+    let probe = network_root.join(PROBE_FILE_NAME);
+    let written = fs::File::create(&probe)
+        .and_then(|mut file| file.write_all(b"emudeck_sync"))
+        .and_then(|()| fs::remove_file(&probe));
+    match written {
+        Ok(()) => {
+            checklist.report(
+                Status::Pass,
+                format!(
+                    "network emulation directory {} is writable",
+                    network_root.display()
+                ),
+            );
+            true
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&probe);
+            checklist.report(
+                Status::Fail,
+                format!(
+                    "network emulation directory {} is not writable: {}",
+                    network_root.display(),
+                    e
+                ),
+            );
+            false
+        }
+    }
+}
+
+fn check_free_space(
+    checklist: &mut Checklist,
+    local_root: &Path,
+    network_root: &Path,
+    state_dir: &Path,
+    sync_options: &SyncOptions,
+) {
+    let mut manifest = Manifest::load(&state_dir.join("manifest.json"));
+    let estimate =
+        match sync::estimate_transfer(sync_options, local_root, network_root, &mut manifest) {
+            Ok(estimate) => estimate,
+            Err(e) => {
+                checklist.report(
+                    Status::Fail,
+                    format!("could not estimate the sync size: {}", e),
+                );
+                return;
+            }
+        };
+    let available = match fs2::available_space(network_root) {
+        Ok(available) => available,
+        Err(e) => {
+            checklist.report(
+                Status::Warn,
+                format!("could not read the free space on the network: {}", e),
+            );
+            return;
+        }
+    };
+
+    let message = format!(
+        "sync needs {} for {} files, {} free on the network",
+        format_bytes(estimate.bytes),
+        estimate.files,
+        format_bytes(available)
+    );
+    if estimate.bytes > available {
+        checklist.report(Status::Fail, message);
+    } else if estimate.bytes as f64 > available as f64 * SPACE_WARNING_RATIO {
+        checklist.report(Status::Warn, message);
+    } else {
+        checklist.report(Status::Pass, message);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_watch_limit(checklist: &mut Checklist, watch_options: &WatchOptions) {
+    use notify::RecursiveMode;
+
+    let limit = match fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+        .ok()
+        .and_then(|contents| contents.trim().parse::<u64>().ok())
+    {
+        Some(limit) => limit,
+        None => {
+            checklist.report(
+                Status::Warn,
+                "could not read the inotify watch limit".to_string(),
+            );
+            return;
+        }
+    };
+
+    let in_use = inotify_watches_in_use();
+    let needed: u64 = watch::watch_targets(watch_options)
+        .iter()
+        .map(|(path, mode)| match mode {
+            RecursiveMode::Recursive => count_directories(path),
+            RecursiveMode::NonRecursive => 1,
+        })
+        .sum();
+    let headroom = limit.saturating_sub(in_use);
+    let message = format!(
+        "watching needs {} inotify watches, {} of {} are free",
+        needed, headroom, limit
+    );
+    if needed > headroom {
+        checklist.report(Status::Fail, message);
+    } else if needed as f64 > headroom as f64 * WATCH_WARNING_RATIO {
+        checklist.report(Status::Warn, message);
+    } else {
+        checklist.report(Status::Pass, message);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_watch_limit(_checklist: &mut Checklist, _watch_options: &WatchOptions) {}
+
+/// Counts the inotify watches held by every process we are allowed to inspect.
+#[cfg(target_os = "linux")]
+fn inotify_watches_in_use() -> u64 {
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return 0;
+    };
+    processes
+        .flatten()
+        .filter_map(|process| fs::read_dir(process.path().join("fdinfo")).ok())
+        .flat_map(|descriptors| descriptors.flatten())
+        .filter_map(|descriptor| fs::read_to_string(descriptor.path()).ok())
+        .map(|info| {
+            info.lines()
+                .filter(|line| line.starts_with("inotify wd:"))
+                .count() as u64
+        })
+        .sum()
+}
+
+/// A recursive watch takes one inotify watch per directory, the root included. A directory that
+/// does not exist is counted as one.
+#[cfg(target_os = "linux")]
+fn count_directories(root: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(root) else {
+        return 1;
+    };
+    1 + entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .map(|entry| count_directories(&entry.path()))
+        .sum::<u64>()
+}
+
+fn check_lock(checklist: &mut Checklist, state_dir: &Path) {
+    match lock::is_held(state_dir) {
+        Ok(false) => checklist.report(
+            Status::Pass,
+            format!(
+                "no other instance is using state directory {}",
+                state_dir.display()
+            ),
+        ),
+        Ok(true) => checklist.report(
+            Status::Warn,
+            format!(
+                "another instance holds the lock on state directory {}",
+                state_dir.display()
+            ),
+        ),
+        Err(e) => checklist.report(
+            Status::Fail,
+            format!(
+                "could not check the lock in state directory {}: {}",
+                state_dir.display(),
+                e
+            ),
+        ),
+    }
+}
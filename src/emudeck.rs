@@ -0,0 +1,107 @@
+use clap::ValueEnum;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Installations whose Emulation folder can be found without spelling out its path.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum EmuDeckRoot {
+    /// Find the Emulation folder through EmuDeck's settings, Steam's library folders and the usual
+    /// Steam Deck mount points, so it is found even after the SD card was remounted elsewhere
+    Steamdeck,
+}
+
+/// Locates the EmuDeck Emulation folder, or `None` if no candidate exists.
+pub fn discover(root: EmuDeckRoot) -> Option<PathBuf> {
+    match root {
+        EmuDeckRoot::Steamdeck => candidates()
+            .into_iter()
+            .inspect(|candidate| debug!("emudeck root candidate: {}", candidate.display()))
+            .find(|candidate| candidate.is_dir()),
+    }
+}
+
+/// Candidates in order of preference: where EmuDeck says it installed to, the root of every Steam
+/// library (EmuDeck puts Emulation next to the SD card library), the internal default, then any
+/// removable media SteamOS has mounted.
+fn candidates() -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let mut candidates = Vec::new();
+
+    if let Some(home) = &home {
+        for settings in [
+            home.join(".config/EmuDeck/settings.sh"),
+            home.join("emudeck/settings.sh"),
+        ] {
+            candidates.extend(emudeck_emulation_path(&settings));
+        }
+
+        for library_folders in [
+            home.join(".local/share/Steam/steamapps/libraryfolders.vdf"),
+            home.join(".steam/steam/steamapps/libraryfolders.vdf"),
+        ] {
+            candidates.extend(
+                steam_library_paths(&library_folders)
+                    .into_iter()
+                    .map(|library| library.join("Emulation")),
+            );
+        }
+
+        candidates.push(home.join("Emulation"));
+    }
+
+    for media in ["/run/media", "/run/media/deck"] {
+        if let Ok(entries) = fs::read_dir(media) {
+            let mut mounts: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+            mounts.sort();
+            candidates.extend(mounts.into_iter().map(|mount| mount.join("Emulation")));
+        }
+    }
+
+    candidates
+}
+
+/// The `emulationPath=...` setting from EmuDeck's shell style settings file.
+fn emudeck_emulation_path(settings: &Path) -> Option<PathBuf> {
+    let contents = fs::read_to_string(settings).ok()?;
+    contents.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("emulationPath=")?;
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        (!value.is_empty()).then(|| PathBuf::from(value))
+    })
+}
+
+/// Every `"path"` value in Steam's `libraryfolders.vdf`. The KeyValues format is a nest of quoted
+/// keys, quoted values and braces, and the library paths are the only `path` keys in it.
+fn steam_library_paths(library_folders: &Path) -> Vec<PathBuf> {
+    let Ok(contents) = fs::read_to_string(library_folders) else {
+        return Vec::new();
+    };
+
+    let tokens = quoted_tokens(&contents);
+    tokens
+        .windows(2)
+        .filter(|pair| pair[0].eq_ignore_ascii_case("path"))
+        .map(|pair| PathBuf::from(&pair[1]))
+        .collect()
+}
+
+fn quoted_tokens(contents: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        if c != '"' {
+            continue;
+        }
+        let mut token = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => token.extend(chars.next()),
+                _ => token.push(c),
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
@@ -0,0 +1,100 @@
+use futures::stream::{self, BoxStream, StreamExt};
+use futures_timer::Delay;
+use notify::{Event, EventKind};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// One line of an event log. Paths are relative to the network root, so a log recorded on one
+/// machine replays against a copy of the tree anywhere else.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// Milliseconds since the watcher started.
+    pub elapsed_ms: u64,
+    pub kind: EventKind,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Appends every event the watcher receives to a JSON lines log.
+pub struct EventRecorder {
+    file: File,
+    root: PathBuf,
+    started: Instant,
+}
+
+impl EventRecorder {
+    pub fn create(path: &Path, root: &Path) -> io::Result<Self> {
+        Ok(EventRecorder {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            root: root.to_path_buf(),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        let recorded = RecordedEvent {
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            kind: event.kind,
+            paths: event
+                .paths
+                .iter()
+                .map(|path| path.strip_prefix(&self.root).unwrap_or(path).to_path_buf())
+                .collect(),
+        };
+        let mut line = serde_json::to_vec(&recorded)?;
+        line.push(b'\n');
+        // One write per line, so a crash never leaves half an event behind.
+        self.file.write_all(&line)
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<RecordedEvent>> {
+    fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} line {}: {}", path.display(), index + 1, e),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Turns a log back into the events it recorded, below `root` and spaced out as they originally
+/// arrived, so batching behaves as it did at the time.
+pub fn replay(
+    events: Vec<RecordedEvent>,
+    root: &Path,
+) -> BoxStream<'static, notify::Result<Event>> {
+    let root = root.to_path_buf();
+    let mut previous_ms = 0;
+    let spaced: Vec<_> = events
+        .into_iter()
+        .map(|recorded| {
+            let gap = Duration::from_millis(recorded.elapsed_ms.saturating_sub(previous_ms));
+            previous_ms = recorded.elapsed_ms;
+            (gap, recorded)
+        })
+        .collect();
+
+    stream::iter(spaced)
+        .then(move |(gap, recorded)| {
+            let root = root.clone();
+            async move {
+                Delay::new(gap).await;
+                Ok(recorded
+                    .paths
+                    .into_iter()
+                    .fold(Event::new(recorded.kind), |event, path| {
+                        event.add_path(root.join(path))
+                    }))
+            }
+        })
+        .boxed()
+}
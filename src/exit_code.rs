@@ -0,0 +1,16 @@
+//! Process exit codes, so scripts and service managers can tell why a run ended.
+
+/// Something went wrong that has no more specific code.
+pub const FAILURE: u8 = 1;
+
+/// The network emulation directory could not be reached.
+pub const NETWORK_UNREACHABLE: u8 = 3;
+
+/// Another instance already holds the lock on the state directory.
+pub const ALREADY_RUNNING: u8 = 4;
+
+/// The initial sync was aborted because `--max-errors` copies failed.
+pub const TOO_MANY_ERRORS: u8 = 5;
+
+/// `--time-limit` ran out with files still to copy, which the next run picks up.
+pub const TIME_LIMITED: u8 = 6;
@@ -0,0 +1,38 @@
+use regex::bytes::Regex;
+use std::path::{Component, Path};
+
+/// Which files are synced at all, by `--include-regex` and `--exclude-regex` on their path
+/// relative to the emulation root, written with `/` separators on every platform. They match the
+/// name's bytes, so names that are not UTF-8 are told apart too; `.` does not match such a byte.
+#[derive(Clone, Default)]
+pub struct PathFilter {
+    /// If any, only files matching one of them are synced.
+    pub include: Vec<Regex>,
+    /// Files matching any of them are skipped, even if an include matches too.
+    pub exclude: Vec<Regex>,
+}
+
+/// Compiles a `--include-regex` or `--exclude-regex`, so a bad one is refused before anything
+/// runs.
+pub fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|e| format!("invalid regex {value}: {e}"))
+}
+
+impl PathFilter {
+    /// Whether the file at `relative` to the local emulation root is synced.
+    pub fn allows(&self, relative: &Path) -> bool {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return true;
+        }
+        let path = relative
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => Some(part.as_encoded_bytes()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join(&b'/');
+        (self.include.is_empty() || self.include.iter().any(|regex| regex.is_match(&path)))
+            && !self.exclude.iter().any(|regex| regex.is_match(&path))
+    }
+}
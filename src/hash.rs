@@ -0,0 +1,64 @@
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::Path;
+use twox_hash::XxHash3_64;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The hashes of a file's content. The fast hash is always computed, the SHA-256 digest only when
+/// cryptographic certainty was asked for.
+#[derive(PartialEq, Eq)]
+pub struct ContentHashes {
+    pub xxh3: u64,
+    pub sha256: Option<String>,
+}
+
+/// Incrementally hashes content as it streams past, so a copy can hash what it writes without a
+/// second read of the source.
+pub struct ContentHasher {
+    fast: XxHash3_64,
+    strong: Option<Sha256>,
+}
+
+impl ContentHasher {
+    pub fn new(strong: bool) -> Self {
+        ContentHasher {
+            fast: XxHash3_64::default(),
+            strong: strong.then(Sha256::new),
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.fast.write(chunk);
+        if let Some(strong) = self.strong.as_mut() {
+            strong.update(chunk);
+        }
+    }
+
+    pub fn finish(self) -> ContentHashes {
+        ContentHashes {
+            xxh3: self.fast.finish(),
+            sha256: self.strong.map(|strong| to_hex(&strong.finalize())),
+        }
+    }
+}
+
+pub fn hash_file(path: &Path, strong: bool) -> io::Result<ContentHashes> {
+    let mut file = File::open(path)?;
+    let mut hasher = ContentHasher::new(strong);
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
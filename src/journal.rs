@@ -0,0 +1,83 @@
+use crate::paths;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// The watcher's queue of paths waiting to be copied, kept on disk so a crash or restart does not
+/// lose them. One path relative to the network root per line, appended as paths are queued and
+/// truncated once the queue is empty. Lines are written without syncing, which survives the
+/// process dying but not the machine losing power.
+pub struct QueueJournal {
+    file: File,
+}
+
+impl QueueJournal {
+    /// Opens the journal at `path`, returning the paths a previous run left queued.
+    pub fn open(path: &Path) -> io::Result<(QueueJournal, BTreeSet<PathBuf>)> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let pending = match fs::read(path) {
+            // Only whole lines, a crash part way through a write leaves the last one torn.
+            Ok(contents) => contents
+                .split_inclusive(|byte| *byte == b'\n')
+                .filter_map(|line| line.strip_suffix(b"\n"))
+                .filter(|line| !line.is_empty())
+                .filter_map(|line| {
+                    let path = paths::path_from_bytes(line);
+                    if path.is_none() {
+                        warn!(
+                            "dropping {} from the watch journal, it is not a path on this platform",
+                            String::from_utf8_lossy(line)
+                        );
+                    }
+                    path
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+        let file = File::options().create(true).append(true).open(path)?;
+        let mut journal = QueueJournal { file };
+        // Rewritten whole, so nothing is ever appended to a torn line.
+        journal.replace(&pending);
+        Ok((journal, pending))
+    }
+
+    /// Records a newly queued path. A path whose name holds a newline cannot be journaled and is
+    /// only queued in memory.
+    pub fn append(&mut self, relative: &Path) {
+        let bytes = relative.as_os_str().as_encoded_bytes();
+        if bytes.contains(&b'\n') {
+            warn!(
+                "not journaling {}, its name contains a newline",
+                relative.display()
+            );
+            return;
+        }
+        let mut line = bytes.to_vec();
+        line.push(b'\n');
+        // A single write per path, so a crash leaves at most one torn line.
+        if let Err(e) = self.file.write_all(&line) {
+            warn!("could not journal {}: {:?}", relative.display(), e);
+        }
+    }
+
+    /// Replaces the journal with `pending`, after some of it was copied.
+    pub fn replace(&mut self, pending: &BTreeSet<PathBuf>) {
+        self.clear();
+        for relative in pending {
+            self.append(relative);
+        }
+    }
+
+    /// Empties the journal once nothing is queued any more.
+    pub fn clear(&mut self) {
+        // Appends always go to the end, so truncating is all it takes.
+        if let Err(e) = self.file.set_len(0) {
+            warn!("could not clear the watch journal: {:?}", e);
+        }
+    }
+}
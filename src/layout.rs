@@ -0,0 +1,113 @@
+use clap::ValueEnum;
+use std::path::{Component, Path, PathBuf};
+
+/// The top-level folder `flat` consolidates.
+const FLAT_FOLDER: &str = "saves";
+/// Joins the path components of a flattened save.
+const SEPARATOR: &str = "__";
+
+/// How paths below the local emulation directory are laid out on the network.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// The same nested layout as the local emulation directory
+    #[default]
+    Mirror,
+    /// Every save directly in the network's saves folder, named <system>__<file>
+    Flat,
+}
+
+impl Layout {
+    /// Where the file at `relative` to the local root goes, relative to the network root.
+    pub fn to_network(self, relative: &Path) -> PathBuf {
+        let Some(rest) = self.flattened_part(relative) else {
+            return relative.to_path_buf();
+        };
+        let components: Option<Vec<String>> = rest
+            .components()
+            .map(|component| component.as_os_str().to_str().map(escape))
+            .collect();
+        match components {
+            Some(components) => Path::new(FLAT_FOLDER).join(components.join(SEPARATOR)),
+            // Names that are not UTF-8 cannot be escaped, they keep their nested path, which the
+            // inverse leaves alone as well.
+            None => relative.to_path_buf(),
+        }
+    }
+
+    /// The inverse of `to_network`, from a path relative to the network root back to the local
+    /// one.
+    pub fn to_local(self, network_relative: &Path) -> PathBuf {
+        if self != Layout::Flat {
+            return network_relative.to_path_buf();
+        }
+        let mut components = network_relative.components();
+        let (Some(Component::Normal(folder)), Some(Component::Normal(name)), None) =
+            (components.next(), components.next(), components.next())
+        else {
+            return network_relative.to_path_buf();
+        };
+        match (folder == FLAT_FOLDER, name.to_str()) {
+            (true, Some(name)) => name
+                .split(SEPARATOR)
+                .map(unescape)
+                .fold(PathBuf::from(FLAT_FOLDER), |path, component| {
+                    path.join(component)
+                }),
+            _ => network_relative.to_path_buf(),
+        }
+    }
+
+    /// Whether directories below `relative` have no counterpart on the network, as their files
+    /// are flattened.
+    pub fn flattens(self, relative: &Path) -> bool {
+        self.flattened_part(relative)
+            .is_some_and(|rest| !rest.as_os_str().is_empty())
+    }
+
+    /// The part of `relative` below the flattened folder, if this layout flattens it.
+    fn flattened_part(self, relative: &Path) -> Option<&Path> {
+        if self != Layout::Flat {
+            return None;
+        }
+        relative.strip_prefix(FLAT_FOLDER).ok()
+    }
+}
+
+/// Percent-escapes `%`, and the underscores that would make a name begin or end with one or hold
+/// a double one, so that joining names with `__` can be undone unambiguously.
+fn escape(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut escaped = String::with_capacity(name.len());
+    for (index, &c) in chars.iter().enumerate() {
+        let ambiguous_underscore = c == '_'
+            && (index == 0
+                || index == chars.len() - 1
+                || chars[index - 1] == '_'
+                || chars[index + 1] == '_');
+        match c {
+            '%' => escaped.push_str("%25"),
+            _ if ambiguous_underscore => escaped.push_str("%5F"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(index) = rest.find('%') {
+        unescaped.push_str(&rest[..index]);
+        rest = &rest[index..];
+        let (c, len) = match rest.get(..3) {
+            Some("%25") => ('%', 3),
+            Some("%5F") => ('_', 3),
+            // Not something `escape` wrote, keep it as it is.
+            _ => ('%', 1),
+        };
+        unescaped.push(c);
+        rest = &rest[len..];
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
@@ -0,0 +1,39 @@
+//! The sync engine behind the `emudeck_sync` binary, a library so that the benchmarks can drive
+//! it in-process.
+
+pub mod bandwidth;
+pub mod battery;
+pub mod catch_up;
+pub mod checksum_cache;
+pub mod compare;
+pub mod config;
+pub mod copy;
+pub mod crypt;
+pub mod dedup;
+pub mod delete;
+pub mod doctor;
+pub mod emudeck;
+pub mod event_log;
+pub mod exit_code;
+pub mod filter;
+pub mod hash;
+pub mod journal;
+pub mod layout;
+pub mod lock;
+pub mod manifest;
+pub mod moves;
+pub mod network;
+pub mod paths;
+pub mod plan;
+pub mod priority;
+pub mod retroarch;
+pub mod runtime;
+pub mod snapshot;
+pub mod sqlite;
+pub mod status;
+pub mod sync;
+pub mod telemetry;
+pub mod transform;
+pub mod units;
+pub mod watch;
+pub mod xattrs;
@@ -0,0 +1,36 @@
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const LOCK_FILE_NAME: &str = "emudeck_sync.lock";
+
+/// Exclusive lock on the state directory, held for the lifetime of a run so two instances never
+/// sync into the same manifest at once. Released when dropped.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Takes the lock, or returns `None` if another instance already holds it.
+    pub fn acquire(state_dir: &Path) -> io::Result<Option<InstanceLock>> {
+        fs::create_dir_all(state_dir)?;
+        let file = File::create(lock_path(state_dir))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(InstanceLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+/// Whether another instance currently holds the lock for `state_dir`.
+pub fn is_held(state_dir: &Path) -> io::Result<bool> {
+    if !lock_path(state_dir).exists() {
+        return Ok(false);
+    }
+    Ok(InstanceLock::acquire(state_dir)?.is_none())
+}
+
+fn lock_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(LOCK_FILE_NAME)
+}
@@ -1,50 +1,598 @@
 use clap::Parser;
-use env_logger::{Builder, Env, Target};
-use fs_extra::dir::{self, CopyOptions, TransitProcess};
-use futures::{
-    channel::mpsc::{channel, Receiver},
-    SinkExt, StreamExt,
+use emudeck_sync::bandwidth::{BandwidthSchedule, RateLimiter, RateWindow};
+use emudeck_sync::battery::BatteryThrottle;
+use emudeck_sync::checksum_cache::ChecksumCache;
+use emudeck_sync::compare::{CompareBy, Verify};
+use emudeck_sync::config::Config;
+use emudeck_sync::copy::CopyOptions;
+use emudeck_sync::crypt::Encryption;
+use emudeck_sync::delete::{DeleteOptions, Deleter};
+use emudeck_sync::emudeck::EmuDeckRoot;
+use emudeck_sync::filter::PathFilter;
+use emudeck_sync::layout::Layout;
+use emudeck_sync::lock::InstanceLock;
+use emudeck_sync::manifest::Manifest;
+use emudeck_sync::plan::Plan;
+use emudeck_sync::priority::{IoClass, Priority};
+use emudeck_sync::retroarch::PathRewriter;
+use emudeck_sync::runtime::Runtime;
+use emudeck_sync::snapshot::SnapshotOptions;
+use emudeck_sync::sqlite::SqliteSafe;
+use emudeck_sync::status::{Direction, RunStatus};
+use emudeck_sync::sync::{SyncError, SyncOptions};
+use emudeck_sync::telemetry::{QuietUnchanged, EVENTS};
+use emudeck_sync::transform::{Pipeline, Transform};
+use emudeck_sync::watch::{NetworkDownAction, WatchError, WatchMode, WatchOptions};
+use emudeck_sync::{
+    bandwidth, catch_up, checksum_cache, config, copy, crypt, dedup, delete, doctor, emudeck,
+    exit_code, filter, paths, plan, priority, retroarch, snapshot, sqlite, status, sync, telemetry,
+    units, watch, xattrs,
 };
-use log::{error, info};
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::Path;
+use regex::bytes::Regex;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     /// Where to find emulator files
-    local_emulation_directory: String,
+    #[arg(
+        required_unless_present_any = ["emudeck_root", "status", "check_config"],
+        value_parser = paths::ExpandedPath
+    )]
+    local_emulation_directory: Option<PathBuf>,
 
     /// Where to find emulator files
-    network_emulation_directory: String,
+    #[arg(
+        required_unless_present_any = ["emudeck_root", "status", "check_config"],
+        value_parser = paths::ExpandedPath
+    )]
+    network_emulation_directory: Option<PathBuf>,
+
+    /// Discover the local emulation directory instead of passing it, the only directory argument
+    /// is then the network emulation directory
+    #[arg(long, value_enum)]
+    emudeck_root: Option<EmuDeckRoot>,
+
+    /// Keep this device's files in a <DEVICE_ID> folder of the network emulation directory, so
+    /// devices sharing it never overwrite each other
+    #[arg(long, value_parser = paths::parse_device_id)]
+    device_id: Option<String>,
+
+    /// How files that already exist on the other side are judged up to date
+    #[arg(long, value_enum, default_value_t = CompareBy::Existence)]
+    compare_by: CompareBy,
+
+    /// Confirm with SHA-256 that copies were written correctly. --verify alone checks only the
+    /// files copied this run, --verify=all also every file found up to date
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "changed"
+    )]
+    verify: Option<Verify>,
+
+    /// Where to keep the sync manifest [default: $XDG_STATE_HOME/emudeck_sync]
+    #[arg(long, value_parser = paths::ExpandedPath)]
+    state_dir: Option<PathBuf>,
+
+    /// What the watcher does while the network emulation directory is unreachable
+    #[arg(long, value_enum, default_value_t = NetworkDownAction::Wait)]
+    network_down_action: NetworkDownAction,
+
+    /// How changes on the network emulation directory are noticed, use poll for NFS/SMB shares
+    /// written to by other machines
+    #[arg(long, value_enum, default_value_t = WatchMode::Native)]
+    watch_mode: WatchMode,
+
+    /// How often --watch-mode poll re-scans the network emulation directory
+    #[arg(long, value_parser = units::parse_duration, default_value = "30s")]
+    poll_interval: Duration,
+
+    /// Watch only the network emulation directory's own entries instead of its whole tree, to
+    /// save inotify watches on large libraries
+    #[arg(long)]
+    non_recursive: bool,
+
+    /// With --non-recursive, also watch this folder's tree, relative to the network emulation
+    /// directory (repeatable, e.g. --include-folder saves)
+    #[arg(long, requires = "non_recursive")]
+    include_folder: Vec<PathBuf>,
+
+    /// Append every event the watcher receives to this JSON lines file, for --replay
+    #[arg(long, value_parser = paths::ExpandedPath)]
+    record_events: Option<PathBuf>,
+
+    /// Feed a --record-events log through the watcher against these directories instead of
+    /// watching, skipping the initial sync, then exit
+    #[arg(long, value_parser = paths::ExpandedPath)]
+    replay: Option<PathBuf>,
+
+    /// How many changed network files the watcher copies at once, kept low so a burst of changes
+    /// cannot swamp a weak NAS
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..), default_value_t = 2)]
+    max_concurrent_network_events: u16,
+
+    /// How often the watcher logs a heartbeat with its uptime and totals, 0 never does
+    #[arg(long, value_parser = units::parse_duration, default_value = "15m")]
+    report_interval: Duration,
+
+    /// Octal mode for directories we create, instead of relying on the umask (Unix only)
+    #[arg(long, value_parser = copy::parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Octal mode for files we write, instead of copying the source's mode (Unix only)
+    #[arg(long, value_parser = copy::parse_mode)]
+    file_mode: Option<u32>,
+
+    /// Let an empty file replace a non-empty one, which is otherwise refused to protect saves
+    /// caught mid-write
+    #[arg(long)]
+    allow_shrink: bool,
+
+    /// Copy files' extended attributes, such as SELinux labels and Samba DOS attributes, along
+    /// with their content (Unix only)
+    #[arg(long)]
+    xattrs: bool,
+
+    /// With --xattrs, copy POSIX ACLs too (Linux only)
+    #[arg(long, requires = "xattrs")]
+    acls: bool,
+
+    /// Cap on copy bandwidth per second, e.g. 2MiB, outside any --bwlimit-schedule window
+    #[arg(long, value_parser = units::parse_bytes)]
+    max_rate: Option<u64>,
+
+    /// Bandwidth for a time of day, e.g. 08:00-23:00=1MiB or 23:00-08:00=unlimited. Repeatable,
+    /// the first matching window wins, and replaces the config file's schedule
+    #[arg(long, value_parser = bandwidth::parse_rate_window)]
+    bwlimit_schedule: Vec<RateWindow>,
+
+    /// Encrypt files matching the config file's encrypt patterns (saves and states by default)
+    /// before they reach the network, and decrypt them on the way back. Adds the encrypt
+    /// transform after the config file's transforms unless they list it. The passphrase comes
+    /// from --passphrase-file or EMUDECK_SYNC_PASSPHRASE
+    #[arg(long)]
+    encrypt: bool,
+
+    /// File holding the encrypt transform's passphrase
+    #[arg(long, value_parser = paths::ExpandedPath)]
+    passphrase_file: Option<PathBuf>,
+
+    /// On startup, first copy the COUNT most recently modified files in the config file's
+    /// save_folders (saves and states by default) that need copying, so the last games played are
+    /// backed up within seconds, then run the full sync
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    prioritize_recent_saves: Option<u32>,
+
+    /// Copy SQLite databases matching the config file's sqlite_safe patterns (*.db and *.sqlite
+    /// by default) to the network through SQLite's backup API, so ones a frontend has open arrive
+    /// consistent. Their -wal, -shm and -journal files are left out
+    #[arg(long)]
+    sqlite_safe: bool,
+
+    /// Start the initial sync even if it does not look like it will fit on the network
+    #[arg(long)]
+    ignore_space: bool,
+
+    /// Scheduling niceness from -20 to 19, e.g. 19 to stay out of the way of a running game
+    #[arg(long, allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    nice: Option<i32>,
+
+    /// I/O scheduling class, idle only reads and writes while nothing else does (Linux only)
+    #[arg(long, value_enum)]
+    ioclass: Option<IoClass>,
+
+    /// Priority within --ioclass best-effort from 0 (highest) to 7
+    #[arg(long, requires = "ioclass", value_parser = clap::value_parser!(u8).range(0..=7), default_value_t = 4)]
+    ioprio: u8,
+
+    /// Abort the initial sync after this many files failed to copy, 0 never aborts
+    #[arg(long, default_value_t = 100)]
+    max_errors: usize,
+
+    /// How files are laid out on the network. flat puts every save directly in the network's
+    /// saves folder as <system>__<file>, for consolidating saves, and maps them back when copying
+    /// to the local side
+    #[arg(long, value_enum, default_value_t = Layout::Mirror)]
+    format_destination: Layout,
+
+    /// Also create directories that are empty in the local emulation directory, such as bios
+    /// placeholders. Off by default, which only creates the directories files are copied into
+    #[arg(long)]
+    sync_empty_dirs: bool,
+
+    /// Do not descend into directories of the local emulation directory that are on another
+    /// filesystem, such as a bind-mounted SD card, like rsync -x (Unix only)
+    #[arg(long)]
+    one_file_system: bool,
+
+    /// Treat the local emulation directory as possibly stale, such as a read-only Btrfs
+    /// snapshot: the initial sync never overwrites a network file that is newer than the local one
+    #[arg(long)]
+    source_not_authoritative: bool,
+
+    /// Remember file checksums in the state directory by device, inode, size and mtime, so that
+    /// --compare-by checksum and move detection do not reread files that are unchanged (Unix only)
+    #[arg(long)]
+    checksum_cache: bool,
+
+    /// Only sync files whose path relative to the emulation directory, with / separators,
+    /// matches this regex (repeatable, any one matching is enough)
+    #[arg(long, value_name = "RE", value_parser = filter::parse_regex)]
+    include_regex: Vec<Regex>,
+
+    /// Skip files whose path relative to the emulation directory, with / separators, matches
+    /// this regex, e.g. 'states/.*\.state[1-9]$' (repeatable, wins over --include-regex)
+    #[arg(long, value_name = "RE", value_parser = filter::parse_regex)]
+    exclude_regex: Vec<Regex>,
+
+    /// Abort the initial sync at the first file that cannot be read or copied, instead of
+    /// skipping unreadable and special files and counting failed copies against --max-errors.
+    /// Catch-up, --pull-missing-only and --snapshot abort at an unreadable folder too
+    #[arg(long)]
+    strict: bool,
+
+    /// Delete files on the network that no longer exist in the local emulation directory
+    #[arg(long)]
+    delete_extraneous: bool,
+
+    /// Rename directories on the network that were renamed or moved locally, recognised by their
+    /// file names and sizes (and content with --compare-by checksum), instead of deleting and
+    /// recopying them
+    #[arg(long, requires = "delete_extraneous")]
+    detect_moves: bool,
+
+    /// Ask on the terminal before deleting anything, batched per folder (interactive runs only)
+    #[arg(long)]
+    confirm_deletes: bool,
+
+    /// Move deleted files into this directory, below a folder named for when they were deleted and
+    /// keeping their relative paths, instead of removing them. Must be on the same filesystem as
+    /// the network emulation directory
+    #[arg(long, value_parser = paths::ExpandedPath)]
+    trash_dir: Option<PathBuf>,
+
+    /// How long --trash-dir keeps deleted files before pruning them, 0 keeps them forever
+    #[arg(long, value_parser = units::parse_duration, default_value = "30d")]
+    trash_keep: Duration,
+
+    /// Also write a dated snapshot of the local emulation directory, hard linking files unchanged
+    /// since the previous snapshot
+    #[arg(long)]
+    snapshot: bool,
+
+    /// Where --snapshot keeps its snapshots, must support hard links [default:
+    /// <NETWORK_EMULATION_DIRECTORY>/snapshots]
+    #[arg(long, value_parser = paths::ExpandedPath)]
+    snapshot_dir: Option<PathBuf>,
+
+    /// How many snapshots to keep, 0 keeps them all
+    #[arg(long, default_value_t = 10)]
+    snapshot_keep: usize,
+
+    /// Pause copying ROMs and other large folders during the initial sync while discharging
+    /// below --battery-threshold, saves still copy
+    #[arg(long)]
+    throttle_on_battery: bool,
+
+    /// Battery percentage below which --throttle-on-battery pauses
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100), default_value_t = 30)]
+    battery_threshold: u8,
+
+    /// Before the initial sync, apply changes made on either side while we were not running, as
+    /// told by the manifest
+    #[arg(long)]
+    since_last_run: bool,
+
+    /// Export traces of every sync to this OTLP/HTTP collector endpoint, e.g.
+    /// http://localhost:4318/v1/traces
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Stop the run after this long, e.g. 2h: the initial sync starts no further copies and the
+    /// watcher stops. Exits with code 6 if files are left for the next run
+    #[arg(long, value_parser = units::parse_duration)]
+    time_limit: Option<Duration>,
+
+    /// TOML config file [default: $XDG_CONFIG_HOME/emudeck_sync/config.toml]
+    #[arg(long, value_parser = paths::ExpandedPath)]
+    config: Option<PathBuf>,
+
+    /// Validate the config file, print it as a run with these options would use it, defaults
+    /// filled in, then exit without syncing
+    #[arg(long)]
+    check_config: bool,
+
+    /// Check paths, mount, permissions, free space and watch limits, then exit without syncing
+    #[arg(long)]
+    doctor: bool,
+
+    /// Report files with identical content in the local emulation directory, largest saving
+    /// first, then exit without syncing
+    #[arg(long)]
+    dedup_report: bool,
+
+    /// Work out what the initial sync would rename, copy and delete, write that plan to FILE as
+    /// JSON for review, then exit without syncing
+    #[arg(long, value_name = "FILE", value_parser = paths::ExpandedPath, conflicts_with = "apply")]
+    plan: Option<PathBuf>,
+
+    /// Carry out a plan written by --plan, without rescanning either directory, then exit.
+    /// Actions whose files changed since the plan was made are left out with a warning
+    #[arg(long, value_name = "FILE", value_parser = paths::ExpandedPath)]
+    apply: Option<PathBuf>,
+
+    /// Copy the network files that do not exist locally, never overwriting or deleting a local
+    /// file, then exit. For setting up a new device that may already have saves of its own
+    #[arg(long, conflicts_with_all = ["plan", "apply"])]
+    pull_missing_only: bool,
+
+    /// Print what the last sync or watcher batch did and whether the watcher is running, from the
+    /// state directory, then exit without syncing
+    #[arg(long)]
+    status: bool,
+
+    /// Print nothing but one line for a run that copies, deletes and fails nothing, and the usual
+    /// output once it does. --quiet-unchanged=silent leaves out the line too
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "summary",
+        conflicts_with_all = ["status", "check_config", "doctor", "dedup_report", "plan"]
+    )]
+    quiet_unchanged: Option<QuietUnchanged>,
+
+    /// Print --dedup-report and --status as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// The local emulation directory, once passed or discovered.
+    #[arg(skip)]
+    local_root: PathBuf,
+
+    /// The network emulation directory, or this device's folder in it.
+    #[arg(skip)]
+    network_root: PathBuf,
+
+    /// The limiter every copy shares, once the schedule is known.
+    #[arg(skip)]
+    rate_limit: Option<Arc<Mutex<RateLimiter>>>,
+
+    /// The config file's transforms and --encrypt, once set up.
+    #[arg(skip)]
+    transforms: Pipeline,
+
+    /// The databases --sqlite-safe covers, once its patterns are parsed.
+    #[arg(skip)]
+    sqlite_databases: Option<Arc<SqliteSafe>>,
+
+    /// The --checksum-cache, once the state directory is locked.
+    #[arg(skip)]
+    checksums: Option<Arc<ChecksumCache>>,
+
+    /// When --time-limit runs out, counted from startup.
+    #[arg(skip)]
+    deadline: Option<Instant>,
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
-    init_logging();
+    let telemetry = telemetry::init(cli.otlp_endpoint.as_deref(), cli.quiet_unchanged);
+    let code = run(cli);
+    telemetry.shutdown();
+    code
+}
+
+fn run(mut cli: Cli) -> ExitCode {
+    // Before anything is logged, so --json prints nothing but JSON.
+    if cli.status {
+        return match status::read(&state_directory(&cli))
+            .and_then(|status| status::print_status(status.as_ref(), cli.json))
+        {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("status error: {:?}", e);
+                ExitCode::from(exit_code::FAILURE)
+            }
+        };
+    }
+
+    // Before anything is logged too, so the config printed can be saved and read back.
+    if cli.check_config {
+        return check_config(&cli);
+    }
+
+    // A limit too far out to represent is no limit.
+    cli.deadline = cli
+        .time_limit
+        .and_then(|limit| Instant::now().checked_add(limit));
     log_app_name_and_version();
+    priority::apply(&Priority {
+        nice: cli.nice,
+        io_class: cli.ioclass,
+        io_priority: cli.ioprio,
+    });
+    if let Err(e) = resolve_roots(&mut cli) {
+        error!("{}", e);
+        return ExitCode::from(exit_code::FAILURE);
+    }
     log_emulation_locations(&cli);
+    copy::check_modes_supported(&copy_options(&cli));
+    paths::check_one_file_system_supported(cli.one_file_system);
+    checksum_cache::check_supported(cli.checksum_cache);
+    xattrs::check_supported(cli.xattrs);
+    if let Err(e) = delete::check_confirm_supported(&delete_options(&cli)) {
+        error!("{}", e);
+        return ExitCode::from(exit_code::FAILURE);
+    }
+
+    let config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("config error: {}", e);
+            return ExitCode::from(exit_code::FAILURE);
+        }
+    };
+    cli.rate_limit = rate_limiter(&cli, &config);
+
+    let state_dir = state_directory(&cli);
+    if cli.doctor {
+        let healthy = doctor::run(
+            &cli.local_root,
+            &cli.network_root,
+            &state_dir,
+            &sync_options(&cli, &config),
+            &watch_options(&cli),
+        );
+        return if healthy {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::from(exit_code::FAILURE)
+        };
+    }
+
+    if cli.dedup_report {
+        let _span = info_span!("dedup_report").entered();
+        let manifest = Manifest::load(&state_dir.join("manifest.json"));
+        let result = dedup::find_duplicates(&cli.local_root, &manifest, cli.verify.is_some())
+            .and_then(|report| dedup::print_report(&report, cli.json));
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("dedup report error: {:?}", e);
+                ExitCode::from(exit_code::FAILURE)
+            }
+        };
+    }
+
+    let _lock = match InstanceLock::acquire(&state_dir) {
+        Ok(Some(lock)) => lock,
+        Ok(None) => {
+            error!(
+                "another instance is already using state directory {}",
+                state_dir.display()
+            );
+            return ExitCode::from(exit_code::ALREADY_RUNNING);
+        }
+        Err(e) => {
+            error!(
+                "could not lock state directory {}: {:?}",
+                state_dir.display(),
+                e
+            );
+            return ExitCode::from(exit_code::FAILURE);
+        }
+    };
+
+    match transforms(&cli, &config) {
+        Ok(transforms) => cli.transforms = transforms,
+        Err(e) => {
+            error!("transform error: {}", e);
+            return ExitCode::from(exit_code::FAILURE);
+        }
+    }
+    if cli.sqlite_safe {
+        match config::parse_globs(
+            &config.sqlite_safe,
+            &sqlite::DEFAULT_PATTERNS,
+            "sqlite_safe",
+        ) {
+            Ok(patterns) => cli.sqlite_databases = Some(Arc::new(SqliteSafe::new(patterns))),
+            Err(e) => {
+                error!("config error: {}", e);
+                return ExitCode::from(exit_code::FAILURE);
+            }
+        }
+    }
+
+    if cli.checksum_cache && cfg!(unix) {
+        cli.checksums = Some(Arc::new(ChecksumCache::load(&state_dir)));
+    }
+
+    if let Some(path) = &cli.plan {
+        return write_plan(&cli, &config, path);
+    }
+    if let Some(path) = &cli.apply {
+        return apply_plan(&cli, &config, path);
+    }
+    if cli.pull_missing_only {
+        return pull_missing(&cli, &config);
+    }
+
+    // Watch from before the initial sync, so changes made on the network while it runs are not
+    // missed.
+    let watch_options = Arc::new(watch_options(&cli));
+    if let Err(e) = watch::resume_journal(&watch_options) {
+        return watch_exit_code(e);
+    }
+    let session = match watch::start_watch(&watch_options) {
+        Ok(session) => session,
+        Err(e) => return watch_exit_code(e),
+    };
 
     // The EmuDeck installation might have been updated, make sure the network file system is
     // up to date. New ROMs can go in the appropriate directories. This also ensures saves are
     // pushed to the NAS.
-    sync_emudeck_to_network_directories(&cli);
-
-    futures::executor::block_on(async {
-        if let Err(e) = async_watch(cli.network_emulation_directory).await {
-            error!("error: {:?}", e)
+    if cli.replay.is_none() {
+        if let Err(code) = sync_emudeck_to_network_directories(&cli, &config) {
+            return code;
         }
-    });
+    }
+
+    let manifest = Manifest::load(&state_dir.join("manifest.json"));
+    let mut runtime = Runtime::default();
+    runtime.spawn(
+        "network watcher",
+        watch::async_watch(session, watch_options, Arc::new(manifest)),
+    );
+    match runtime.run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => watch_exit_code(e),
+    }
 }
 
-fn init_logging() {
-    let env = Env::new()
-        .filter_or("MIN_LEVEL", "info")
-        .write_style_or("STYLE", "always");
-    let mut builder = Builder::from_env(env);
-    builder.target(Target::Stdout);
-    builder.init();
+fn watch_exit_code(e: WatchError) -> ExitCode {
+    match e {
+        WatchError::NetworkUnreachable(path) => {
+            error!(
+                "network emulation directory {} is unreachable, exiting",
+                path.display()
+            );
+            ExitCode::from(exit_code::NETWORK_UNREACHABLE)
+        }
+        WatchError::InvalidRoot(path, reason) => {
+            error!(
+                "cannot watch network emulation directory {}: {}",
+                path.display(),
+                reason
+            );
+            ExitCode::from(exit_code::FAILURE)
+        }
+        WatchError::EventLog(e) => {
+            error!("event log error: {:?}", e);
+            ExitCode::from(exit_code::FAILURE)
+        }
+        WatchError::Notify(e) => {
+            error!("error: {:?}", e);
+            ExitCode::from(exit_code::FAILURE)
+        }
+        WatchError::TimeLimited(queued) => {
+            warn!(target: EVENTS,
+                "time limit reached, leaving {} queued network changes for the next run",
+                queued
+            );
+            ExitCode::from(exit_code::TIME_LIMITED)
+        }
+    }
 }
 
 fn log_app_name_and_version() {
@@ -53,105 +601,500 @@ fn log_app_name_and_version() {
     info!("starting up {name} {version}");
 }
 
+/// Fills in `local_root` and `network_root` from the directory arguments, discovering the local
+/// one if `--emudeck-root` asks for it.
+fn resolve_roots(cli: &mut Cli) -> Result<(), String> {
+    let (local, network) = match (
+        cli.emudeck_root,
+        &cli.local_emulation_directory,
+        &cli.network_emulation_directory,
+    ) {
+        (None, Some(local), Some(network)) => (local.clone(), network.clone()),
+        // With --emudeck-root the single directory argument is the network one.
+        (Some(root), Some(network), None) => {
+            let local = emudeck::discover(root).ok_or_else(|| {
+                "--emudeck-root could not find an EmuDeck Emulation folder".to_string()
+            })?;
+            info!("discovered local emulation directory {}", local.display());
+            (local, network.clone())
+        }
+        (Some(_), Some(_), Some(_)) => {
+            return Err(
+                "--emudeck-root replaces the local emulation directory, pass only the network emulation directory"
+                    .to_string(),
+            )
+        }
+        _ => return Err("the network emulation directory is required".to_string()),
+    };
+
+    cli.local_root = local;
+    cli.network_root = paths::device_root(&network, cli.device_id.as_deref());
+    Ok(())
+}
+
 fn log_emulation_locations(cli: &Cli) {
-    info!(
-        "local emulation directory: {}",
-        cli.local_emulation_directory
-    );
+    info!("local emulation directory: {}", cli.local_root.display());
     info!(
         "network emulation directory: {}",
-        cli.network_emulation_directory
+        cli.network_root.display()
     );
 }
 
-fn sync_emudeck_to_network_directories(cli: &Cli) {
-    let mut options = CopyOptions::new();
-    options.overwrite = false;
-    options.skip_exist = true;
-    options.copy_inside = false;
-    options.content_only = true;
+/// Writes what the initial sync would do to `path`, for `--plan`.
+fn write_plan(cli: &Cli, config: &Config, path: &Path) -> ExitCode {
+    let _span = info_span!("plan").entered();
+    let options = sync_options(cli, config);
+    let mut manifest = Manifest::load(&state_directory(cli).join("manifest.json"));
+    let result = sync::plan_sync(&options, &cli.local_root, &cli.network_root, &mut manifest)
+        .and_then(|actions| {
+            let plan = Plan {
+                version: plan::PLAN_VERSION,
+                created_at: status::now(),
+                local_root: cli.local_root.clone(),
+                network_root: cli.network_root.clone(),
+                actions,
+            };
+            plan.save(path)?;
+            Ok(plan.actions.len())
+        });
+    save_checksums(cli);
+    match result {
+        Ok(actions) => {
+            info!("wrote a plan of {} actions to {}", actions, path.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("plan error: {:?}", e);
+            ExitCode::from(exit_code::FAILURE)
+        }
+    }
+}
 
-    info!("syncing network emulation directory with the local emulation directory structure");
+/// Carries out the plan at `path`, for `--apply`.
+fn apply_plan(cli: &Cli, config: &Config, path: &Path) -> ExitCode {
+    let _span = info_span!("apply").entered();
+    let plan = match Plan::load(path) {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("could not read plan {}: {}", path.display(), e);
+            return ExitCode::from(exit_code::FAILURE);
+        }
+    };
+    if plan.local_root != cli.local_root || plan.network_root != cli.network_root {
+        error!(
+            "plan {} was made for {} and {}, not these directories",
+            path.display(),
+            plan.local_root.display(),
+            plan.network_root.display()
+        );
+        return ExitCode::from(exit_code::FAILURE);
+    }
 
-    match sync_directories(
-        options,
-        cli.local_emulation_directory.as_str(),
-        &cli.network_emulation_directory.as_str(),
-    ) {
-        Ok(_event) => {}
-        Err(e) => error!("directory sync error: {:?}", e),
+    let options = sync_options(cli, config);
+    let manifest_path = state_directory(cli).join("manifest.json");
+    let mut manifest = Manifest::load(&manifest_path);
+    let result = sync::apply_plan(
+        &options,
+        &cli.local_root,
+        &cli.network_root,
+        &plan.actions,
+        &mut manifest,
+    );
+    if let Err(e) = manifest.save(&manifest_path) {
+        error!(
+            "could not save manifest {}: {:?}",
+            manifest_path.display(),
+            e
+        );
+    }
+    save_checksums(cli);
+    match result {
+        Ok(stats) => {
+            if stats.changed_anything() {
+                telemetry::release_output();
+            }
+            status::record_run(
+                &state_directory(cli),
+                RunStatus {
+                    finished_at: status::now(),
+                    direction: Direction::Push,
+                    files_copied: stats.files_copied as u64,
+                    bytes_copied: stats.bytes_copied,
+                    files_failed: stats.files_failed as u64,
+                    files_deleted: stats.files_deleted as u64,
+                },
+            );
+            if stats.files_left > 0 {
+                ExitCode::from(exit_code::TIME_LIMITED)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(e @ SyncError::TooManyErrors(_)) => {
+            error!("plan error: {}, exiting", e);
+            ExitCode::from(exit_code::TOO_MANY_ERRORS)
+        }
+        Err(SyncError::Io(e)) => {
+            error!("plan error: {:?}", e);
+            ExitCode::from(exit_code::FAILURE)
+        }
     }
 }
 
-fn async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<notify::Result<Event>>)> {
-    let (mut tx, rx) = channel(1);
-
-    // Automatically select the best implementation for your platform.
-    // You can also access each implementation directly e.g. INotifyWatcher.
-    let watcher = RecommendedWatcher::new(
-        move |res| {
-            futures::executor::block_on(async {
-                tx.send(res).await.unwrap();
-            })
-        },
-        Config::default(),
-    )?;
+fn pull_missing(cli: &Cli, config: &Config) -> ExitCode {
+    let _span = info_span!("pull_missing").entered();
+    let options = sync_options(cli, config);
+    let manifest_path = state_directory(cli).join("manifest.json");
+    let mut manifest = Manifest::load(&manifest_path);
+    let result =
+        catch_up::pull_missing(&options, &cli.local_root, &cli.network_root, &mut manifest);
+    if let Err(e) = manifest.save(&manifest_path) {
+        error!(
+            "could not save manifest {}: {:?}",
+            manifest_path.display(),
+            e
+        );
+    }
+    match result {
+        Ok(stats) => {
+            if stats.files_pulled + stats.files_failed > 0 {
+                telemetry::release_output();
+            }
+            status::record_run(
+                &state_directory(cli),
+                RunStatus {
+                    finished_at: status::now(),
+                    direction: Direction::Pull,
+                    files_copied: stats.files_pulled as u64,
+                    bytes_copied: stats.bytes_pulled,
+                    files_failed: stats.files_failed as u64,
+                    files_deleted: 0,
+                },
+            );
+            if stats.files_left > 0 {
+                ExitCode::from(exit_code::TIME_LIMITED)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(e @ SyncError::TooManyErrors(_)) => {
+            error!("pull error: {}, exiting", e);
+            ExitCode::from(exit_code::TOO_MANY_ERRORS)
+        }
+        Err(SyncError::Io(e)) => {
+            error!("pull error: {:?}", e);
+            ExitCode::from(exit_code::FAILURE)
+        }
+    }
+}
 
-    Ok((watcher, rx))
+fn save_checksums(cli: &Cli) {
+    if let Some(cache) = &cli.checksums {
+        cache.save();
+    }
 }
 
-async fn async_watch<P: AsRef<Path>>(path: P) -> notify::Result<()> {
-    let (mut watcher, mut rx) = async_watcher()?;
+/// Runs the initial sync. Most failures are logged and the watcher still starts, an `Err` carries
+/// the exit code for failures that should end the run instead.
+fn sync_emudeck_to_network_directories(cli: &Cli, config: &Config) -> Result<(), ExitCode> {
+    let options = sync_options(cli, config);
+    let _span = info_span!("initial_sync").entered();
 
-    info!("starting network emulation directory watcher...");
+    info!("syncing network emulation directory with the local emulation directory structure");
 
-    // Add a path to be watched. All files and directories at that path and
-    // below will be monitored for changes.
-    watcher.watch(path.as_ref(), RecursiveMode::Recursive)?;
+    let manifest_path = state_directory(cli).join("manifest.json");
+    let mut manifest = Manifest::load(&manifest_path);
 
-    while let Some(res) = rx.next().await {
-        match res {
-            Ok(event) => handle_file_system_event(event),
-            Err(e) => error!("watch error: {:?}", e),
+    // Ahead of the sync, which would otherwise overwrite network changes under --compare-by.
+    let mut caught_up_in_time = true;
+    if cli.since_last_run {
+        let _span = info_span!("catch_up").entered();
+        match catch_up::catch_up(&options, &cli.local_root, &cli.network_root, &mut manifest) {
+            Ok(stats) => {
+                if stats.files_pulled + stats.files_pushed + stats.files_left > 0 {
+                    telemetry::release_output();
+                }
+                caught_up_in_time = stats.files_left == 0;
+            }
+            Err(e) => error!("catch up error: {:?}", e),
         }
     }
 
-    Ok(())
+    if let Some(count) = cli.prioritize_recent_saves {
+        let _span = info_span!("recent_saves").entered();
+        match sync::sync_recent(
+            &options,
+            &cli.local_root,
+            &cli.network_root,
+            &config.save_folders(),
+            count as usize,
+            &mut manifest,
+        ) {
+            Ok(stats) if stats.changed_anything() => telemetry::release_output(),
+            Ok(_) => {}
+            // The full sync retries whatever failed here, and decides whether to give up.
+            Err(e) => error!("recent saves sync error: {}", e),
+        }
+    }
+
+    let result =
+        match sync::sync_directories(&options, &cli.local_root, &cli.network_root, &mut manifest) {
+            Ok(stats) => {
+                if stats.changed_anything() {
+                    telemetry::release_output();
+                }
+                status::record_run(
+                    &state_directory(cli),
+                    RunStatus {
+                        finished_at: status::now(),
+                        direction: Direction::Push,
+                        files_copied: stats.files_copied as u64,
+                        bytes_copied: stats.bytes_copied,
+                        files_failed: stats.files_failed as u64,
+                        files_deleted: stats.files_deleted as u64,
+                    },
+                );
+                if stats.files_left > 0 || !caught_up_in_time {
+                    Err(ExitCode::from(exit_code::TIME_LIMITED))
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e @ SyncError::TooManyErrors(_)) => {
+                error!("directory sync error: {}, exiting", e);
+                Err(ExitCode::from(exit_code::TOO_MANY_ERRORS))
+            }
+            Err(SyncError::Io(e)) => {
+                error!("directory sync error: {:?}", e);
+                Ok(())
+            }
+        };
+
+    if cli.snapshot {
+        let snapshot_options = snapshot_options(cli);
+        if let Err(e) = snapshot::create_snapshot(
+            &cli.local_root,
+            &snapshot_options,
+            &options.copy,
+            &mut Deleter::new(&options.delete, &snapshot_options.directory),
+        ) {
+            error!("snapshot error: {}", e);
+        }
+    }
+    delete::prune_trash(&options.delete);
+
+    if let Err(e) = manifest.save(&manifest_path) {
+        error!(
+            "could not save manifest {}: {:?}",
+            manifest_path.display(),
+            e
+        );
+    }
+    save_checksums(cli);
+
+    result
 }
 
-fn handle_file_system_event(event: Event) {
-    info!("event: {:?}", event)
+fn sync_options(cli: &Cli, config: &Config) -> SyncOptions {
+    SyncOptions {
+        copy: copy_options(cli),
+        compare_by: compare_by(cli),
+        verify: cli.verify.is_some(),
+        verify_unchanged: cli.verify == Some(Verify::All),
+        ignore_space: cli.ignore_space,
+        folder_order: config.folder_order(),
+        max_errors: cli.max_errors,
+        delete_extraneous: cli.delete_extraneous,
+        detect_moves: cli.detect_moves,
+        delete: delete_options(cli),
+        ignored: ignored_network_paths(cli),
+        battery: cli.throttle_on_battery.then_some(BatteryThrottle {
+            threshold: cli.battery_threshold,
+        }),
+        strict: cli.strict,
+        sync_empty_dirs: cli.sync_empty_dirs,
+        layout: cli.format_destination,
+        one_file_system: cli.one_file_system,
+        filter: path_filter(cli),
+        checksum_cache: cli.checksums.clone(),
+        source_not_authoritative: cli.source_not_authoritative,
+        deadline: cli.deadline,
+    }
 }
 
-fn sync_directories(options: CopyOptions, source: &str, destination: &str) -> std::io::Result<()> {
-    let destination_path = Path::new(destination);
-    if !destination_path.exists() {
-        info!(
-            "network emulation directory: {} does not exist, creating",
-            destination_path.display()
-        );
-        std::fs::create_dir_all(destination)?;
+fn path_filter(cli: &Cli) -> PathFilter {
+    PathFilter {
+        include: cli.include_regex.clone(),
+        exclude: cli.exclude_regex.clone(),
     }
+}
 
-    let handle = |process_info: TransitProcess| {
-        let percentage =
-            (process_info.copied_bytes as f64 / process_info.total_bytes as f64) * 100.0;
+fn watch_options(cli: &Cli) -> WatchOptions {
+    WatchOptions {
+        local_root: cli.local_root.clone(),
+        network_root: cli.network_root.clone(),
+        network_down_action: cli.network_down_action,
+        watch_mode: cli.watch_mode,
+        poll_interval: cli.poll_interval,
+        ignored: ignored_network_paths(cli),
+        copy: copy_options(cli),
+        compare_by: compare_by(cli),
+        non_recursive: cli.non_recursive,
+        include_folders: cli.include_folder.clone(),
+        record_events: cli.record_events.clone(),
+        replay: cli.replay.clone(),
+        report_interval: cli.report_interval,
+        state_dir: state_directory(cli),
+        layout: cli.format_destination,
+        max_concurrent_copies: cli.max_concurrent_network_events.into(),
+        filter: path_filter(cli),
+        checksum_cache: cli.checksums.clone(),
+        deadline: cli.deadline,
+    }
+}
 
-        // Log progress around every 10 percent
-        if (percentage as u32) % 10 == 0 {
-            info!(
-                "emulation folder synchronisation progress: {:.2}%",
-                percentage
-            );
+fn compare_by(cli: &Cli) -> CompareBy {
+    if cli.verify == Some(Verify::All) {
+        CompareBy::Checksum
+    } else {
+        cli.compare_by
+    }
+}
+
+fn delete_options(cli: &Cli) -> DeleteOptions {
+    DeleteOptions {
+        confirm: cli.confirm_deletes,
+        trash: cli.trash_dir.clone(),
+        trash_keep: cli.trash_keep,
+    }
+}
+
+fn snapshot_options(cli: &Cli) -> SnapshotOptions {
+    SnapshotOptions {
+        directory: cli
+            .snapshot_dir
+            .clone()
+            .unwrap_or_else(|| cli.network_root.join("snapshots")),
+        keep: cli.snapshot_keep,
+        one_file_system: cli.one_file_system,
+        strict: cli.strict,
+        filter: path_filter(cli),
+    }
+}
+
+/// Paths under the network emulation directory, relative to it, that the watcher must not copy
+/// back to the local side and the sync must not treat as extraneous.
+fn ignored_network_paths(cli: &Cli) -> Vec<PathBuf> {
+    let mut ignored = vec![PathBuf::from(crypt::SALT_FILE)];
+    let snapshots = cli.snapshot.then(|| snapshot_options(cli).directory);
+    for directory in snapshots.iter().chain(&cli.trash_dir) {
+        if let Ok(relative) = directory.strip_prefix(&cli.network_root) {
+            ignored.push(relative.to_path_buf());
+        }
+    }
+    ignored
+}
+
+fn copy_options(cli: &Cli) -> CopyOptions {
+    CopyOptions {
+        dir_mode: cli.dir_mode,
+        file_mode: cli.file_mode,
+        allow_shrink: cli.allow_shrink,
+        rate_limit: cli.rate_limit.clone(),
+        transforms: cli.transforms.clone(),
+        sqlite_safe: cli.sqlite_databases.clone(),
+        xattrs: cli.xattrs,
+        acls: cli.acls,
+    }
+}
+
+/// Prints the config file merged with the built-in defaults and the options that override it.
+fn check_config(cli: &Cli) -> ExitCode {
+    let config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("config error: {}", e);
+            return ExitCode::from(exit_code::FAILURE);
         }
-        fs_extra::dir::TransitProcessResult::ContinueOrAbort
     };
+    let mut effective = config.effective();
+    if !cli.bwlimit_schedule.is_empty() {
+        effective.bwlimit_schedule = cli.bwlimit_schedule.clone();
+    }
+    effective.transforms = transform_names(cli, &config);
+    match toml::to_string_pretty(&effective) {
+        Ok(contents) => {
+            print!("{contents}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("could not print the config: {:?}", e);
+            ExitCode::from(exit_code::FAILURE)
+        }
+    }
+}
+
+/// The config file's `transforms` in their order, `--encrypt` adding `encrypt` at the end unless
+/// they list it.
+fn transform_names(cli: &Cli, config: &Config) -> Vec<String> {
+    let mut names = config.transforms.clone();
+    if cli.encrypt && !names.iter().any(|name| name == crypt::NAME) {
+        names.push(crypt::NAME.to_string());
+    }
+    names
+}
 
-    // Sync the directories by copying from source to destination
-    match dir::copy_with_progress(Path::new(source), Path::new(destination), &options, handle) {
-        Ok(_result) => {}
-        Err(e) => error!("sync directories error: {:?}", e),
+/// Sets up the transforms of `transform_names`, whose names the config file was checked for when
+/// it was loaded.
+fn transforms(cli: &Cli, config: &Config) -> Result<Pipeline, String> {
+    let names = transform_names(cli, config);
+    let mut transforms: Vec<Arc<dyn Transform>> = Vec::new();
+    for name in &names {
+        match name.as_str() {
+            crypt::NAME => transforms.push(Arc::new(encryption(cli, config)?)),
+            retroarch::NAME => transforms.push(Arc::new(PathRewriter::new(&config.retroarch)?)),
+            _ => unreachable!("unknown transform {name}"),
+        }
+    }
+    if !names.is_empty() {
+        info!("transforms: {}", names.join(", "));
     }
+    Ok(Pipeline::new(transforms))
+}
 
-    Ok(())
+fn encryption(cli: &Cli, config: &Config) -> Result<Encryption, String> {
+    let patterns = crypt::parse_patterns(&config.encrypt)?;
+    let passphrase = crypt::read_passphrase(cli.passphrase_file.as_deref())?;
+    Encryption::new(&passphrase, &cli.network_root, patterns).map_err(|e| {
+        format!(
+            "could not set up encryption in {}: {:?}",
+            cli.network_root.display(),
+            e
+        )
+    })
+}
+
+/// Command line windows replace the config file's rather than adding to them.
+fn rate_limiter(cli: &Cli, config: &Config) -> Option<Arc<Mutex<RateLimiter>>> {
+    let windows = if cli.bwlimit_schedule.is_empty() {
+        config.bwlimit_schedule.clone()
+    } else {
+        cli.bwlimit_schedule.clone()
+    };
+    let schedule = BandwidthSchedule {
+        windows,
+        default_rate: cli.max_rate,
+    };
+    (!schedule.is_unlimited()).then(|| Arc::new(Mutex::new(RateLimiter::new(schedule))))
+}
+
+fn state_directory(cli: &Cli) -> PathBuf {
+    if let Some(state_dir) = &cli.state_dir {
+        return state_dir.clone();
+    }
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_default()
+        .join("emudeck_sync")
 }
@@ -0,0 +1,224 @@
+use crate::paths;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::{info, warn};
+
+/// Bump whenever the on-disk layout changes. A manifest written with any other version is
+/// discarded and rebuilt rather than misread, except those `load` knows how to read.
+pub const MANIFEST_VERSION: u32 = 3;
+
+/// Version 1 kept a transformed file's network copy under `encrypted`, which `stored` is read
+/// from, and is otherwise the same.
+const STORED_AS_ENCRYPTED_VERSION: u32 = 1;
+
+/// Version 2 wrote names lossily, which `load` re-escapes. Names that were not UTF-8 no longer
+/// match and are recorded afresh.
+const LOSSY_NAMES_VERSION: u32 = 2;
+
+/// What was last seen for every synchronised file, keyed by its path relative to the emulation
+/// root. Lets later runs reuse hashes instead of re-reading unchanged files.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub source_mtime_ns: u64,
+    pub destination_mtime_ns: u64,
+    /// xxHash3 (64 bit) of the content, cheap enough to compute for every copy.
+    pub xxh3: Option<u64>,
+    /// SHA-256 of the content, only recorded when `--verify` computed it.
+    pub sha256: Option<String>,
+    /// Set when transforms or `--sqlite-safe` changed the network copy, whose size then differs
+    /// from `size`. Read from `encrypted` in manifests written before transforms.
+    #[serde(default, alias = "encrypted", skip_serializing_if = "Option::is_none")]
+    pub stored: Option<StoredCopy>,
+}
+
+/// The network copy of a file that the transform pipeline or a database snapshot stored, whose
+/// name and size can differ from the local file's. Its entry stays under the local path.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredCopy {
+    /// The transforms that covered the file, in pipeline order.
+    #[serde(default)]
+    pub transforms: Vec<String>,
+    /// The network copy's file name, when a transform renamed it, written with `paths::escape`.
+    /// It stays in the directory the logical path maps to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    /// Size of the network copy.
+    pub size: u64,
+}
+
+/// Which side of a sync a file's metadata was read from.
+#[derive(Clone, Copy)]
+pub enum Side {
+    Source,
+    Destination,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest::new()
+    }
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Reads the manifest at `path`, starting afresh if it is missing, unreadable or was written
+    /// by an incompatible version.
+    pub fn load(path: &Path) -> Self {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Manifest::new(),
+            Err(e) => {
+                warn!("could not read manifest {}: {:?}", path.display(), e);
+                return Manifest::new();
+            }
+        };
+
+        match serde_json::from_str::<Manifest>(&contents) {
+            Ok(manifest) if manifest.version == MANIFEST_VERSION => manifest,
+            Ok(manifest)
+                if manifest.version == STORED_AS_ENCRYPTED_VERSION
+                    || manifest.version == LOSSY_NAMES_VERSION =>
+            {
+                manifest.escape_names()
+            }
+            Ok(manifest) => {
+                info!(
+                    "manifest {} has version {}, expected {}, rebuilding",
+                    path.display(),
+                    manifest.version,
+                    MANIFEST_VERSION
+                );
+                Manifest::new()
+            }
+            Err(e) => {
+                warn!("could not parse manifest {}: {:?}", path.display(), e);
+                Manifest::new()
+            }
+        }
+    }
+
+    /// Writes the manifest atomically, so an interrupted run never leaves a torn file behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// The entry for `relative`, but only if `metadata` shows the file unchanged since it was
+    /// recorded on the given side.
+    pub fn cached(
+        &self,
+        relative: &Path,
+        side: Side,
+        metadata: &Metadata,
+    ) -> Option<&ManifestEntry> {
+        self.files
+            .get(&manifest_key(relative))
+            .filter(|entry| entry.matches(side, metadata))
+    }
+
+    /// The entry for `relative` whatever happened to the file since, for telling which side
+    /// changed.
+    pub fn entry(&self, relative: &Path) -> Option<&ManifestEntry> {
+        self.files.get(&manifest_key(relative))
+    }
+
+    pub fn record(&mut self, relative: &Path, entry: ManifestEntry) {
+        self.files.insert(manifest_key(relative), entry);
+    }
+
+    /// Brings keys and file names written before `paths::escape` up to date, which only changes
+    /// their `%`s.
+    fn escape_names(self) -> Self {
+        let files = self
+            .files
+            .into_iter()
+            .map(|(key, mut entry)| {
+                if let Some(stored) = &mut entry.stored {
+                    stored.file_name = stored
+                        .file_name
+                        .take()
+                        .map(|name| paths::escape(name.as_bytes()));
+                }
+                (paths::escape(key.as_bytes()), entry)
+            })
+            .collect();
+        Manifest {
+            version: MANIFEST_VERSION,
+            files,
+        }
+    }
+}
+
+impl StoredCopy {
+    /// The name a transform gave the network copy, `None` when it kept the local one or the
+    /// recorded name is not one on this platform.
+    pub fn renamed(&self) -> Option<PathBuf> {
+        self.file_name
+            .as_deref()
+            .and_then(paths::unescape)
+            .and_then(|bytes| paths::path_from_bytes(&bytes))
+    }
+}
+
+impl ManifestEntry {
+    /// The transforms the network copy went through, none when it is stored as it is.
+    pub fn stored_transforms(&self) -> &[String] {
+        self.stored
+            .as_ref()
+            .map_or(&[], |stored| stored.transforms.as_slice())
+    }
+
+    /// Whether `metadata` shows the file unchanged since it was recorded on the given side.
+    pub fn matches(&self, side: Side, metadata: &Metadata) -> bool {
+        let (recorded_size, recorded_mtime) = match side {
+            Side::Source => (self.size, self.source_mtime_ns),
+            Side::Destination => (
+                self.stored.as_ref().map_or(self.size, |stored| stored.size),
+                self.destination_mtime_ns,
+            ),
+        };
+        recorded_size == metadata.len() && recorded_mtime == mtime_ns(metadata)
+    }
+}
+
+pub fn mtime_ns(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
+
+/// Manifest keys always use `/` so a manifest reads the same regardless of platform, and each
+/// name is written with `paths::escape` so no two of them share a key.
+fn manifest_key(relative: &Path) -> String {
+    relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(paths::escape(part.as_encoded_bytes())),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,166 @@
+use crate::checksum_cache;
+use crate::compare::CompareBy;
+use crate::copy;
+use crate::sync::SyncOptions;
+use crate::telemetry::{ERRORS, PROGRESS};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The files below a directory, relative to it, with their sizes and, when comparing by checksum,
+/// their fast hashes. Two directories with equal signatures hold the same library.
+type Signature = Vec<(PathBuf, u64, Option<u64>)>;
+
+/// Finds directories that exist only on the source and match a directory that exists only on the
+/// destination, and renames the destination directory into place so the sync that follows has
+/// nothing to copy for it. Returns how many directories were moved.
+pub fn detect_moves(options: &SyncOptions, source: &Path, destination: &Path) -> io::Result<usize> {
+    let mut moved = 0;
+    for (from, to) in find_moves(options, source, destination)? {
+        if apply_move(options, destination, &from, &to)? {
+            moved += 1;
+        }
+    }
+    Ok(moved)
+}
+
+/// The destination directories `detect_moves` would rename, as pairs of where they are and where
+/// the source now has them.
+pub fn find_moves(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut added = Vec::new();
+    unmatched_directories(source, destination, Path::new(""), &[], &mut added)?;
+    // Flattened directories do not exist on the destination to be renamed.
+    added.retain(|relative| !options.layout.flattens(relative));
+    if added.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut removed = Vec::new();
+    unmatched_directories(
+        destination,
+        source,
+        Path::new(""),
+        &options.ignored,
+        &mut removed,
+    )?;
+    if removed.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut candidates = Vec::new();
+    for relative in removed {
+        let signature = signature(options, &destination.join(&relative))?;
+        if !signature.is_empty() {
+            candidates.push((relative, signature));
+        }
+    }
+
+    let mut moves = Vec::new();
+    for relative in added {
+        let signature = signature(options, &source.join(&relative))?;
+        let Some(index) = candidates
+            .iter()
+            .position(|(_, candidate)| !signature.is_empty() && *candidate == signature)
+        else {
+            continue;
+        };
+        let (from, _) = candidates.swap_remove(index);
+        moves.push((from, relative));
+    }
+    Ok(moves)
+}
+
+/// Renames the destination directory `from` to `to`, both relative to `destination`. A failed
+/// rename is only warned about, the sync copies the directory instead. Returns whether it was
+/// renamed.
+pub fn apply_move(
+    options: &SyncOptions,
+    destination: &Path,
+    from: &Path,
+    to: &Path,
+) -> io::Result<bool> {
+    let target = destination.join(to);
+    info!(target: PROGRESS,
+        "{} was moved to {}, renaming it on the network",
+        from.display(),
+        to.display()
+    );
+    if let Some(parent) = target.parent() {
+        copy::create_dir_all(parent, &options.copy)?;
+    }
+    match fs::rename(destination.join(from), &target) {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            warn!(target: ERRORS,
+                "could not rename {} to {}, copying instead: {:?}",
+                from.display(),
+                to.display(),
+                e
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Collects the directories below `root` that have no counterpart below `other`, without
+/// descending into them.
+fn unmatched_directories(
+    root: &Path,
+    other: &Path,
+    relative: &Path,
+    ignored: &[PathBuf],
+    unmatched: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(root.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        if !entry.file_type()?.is_dir() || ignored.iter().any(|path| relative.starts_with(path)) {
+            continue;
+        }
+        if other.join(&relative).is_dir() {
+            unmatched_directories(root, other, &relative, ignored, unmatched)?;
+        } else {
+            unmatched.push(relative);
+        }
+    }
+    Ok(())
+}
+
+fn signature(options: &SyncOptions, directory: &Path) -> io::Result<Signature> {
+    let mut signature = Vec::new();
+    collect_signature(options, directory, Path::new(""), &mut signature)?;
+    Ok(signature)
+}
+
+fn collect_signature(
+    options: &SyncOptions,
+    directory: &Path,
+    relative: &Path,
+    signature: &mut Signature,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_signature(options, directory, &relative, signature)?;
+        } else {
+            let xxh3 = if options.compare_by == CompareBy::Checksum {
+                let checksums = options.checksum_cache.as_deref();
+                Some(checksum_cache::hash_file(checksums, &entry.path(), &metadata, false)?.xxh3)
+            } else {
+                None
+            };
+            signature.push((relative, metadata.len(), xxh3));
+        }
+    }
+    Ok(())
+}
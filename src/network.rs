@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Tracks whether the network emulation directory is currently reachable. A NAS share that drops
+/// out usually leaves its (empty) mount point behind, so a root that was a mount point when the
+/// monitor was created only counts as available while it still is one.
+pub struct NetworkMonitor {
+    root: PathBuf,
+    expect_mount_point: bool,
+}
+
+impl NetworkMonitor {
+    pub fn new(root: &Path) -> Self {
+        NetworkMonitor {
+            root: root.to_path_buf(),
+            expect_mount_point: is_mount_point(root),
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        fs::read_dir(&self.root).is_ok() && (!self.expect_mount_point || is_mount_point(&self.root))
+    }
+}
+
+/// Whether `path` is the root of a mounted filesystem, judged by it living on a different device
+/// than its parent.
+#[cfg(unix)]
+pub fn is_mount_point(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(canonical) = fs::canonicalize(path) else {
+        return false;
+    };
+    let Some(parent) = canonical.parent() else {
+        return true;
+    };
+    match (fs::metadata(&canonical), fs::metadata(parent)) {
+        (Ok(metadata), Ok(parent_metadata)) => metadata.dev() != parent_metadata.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_mount_point(_path: &Path) -> bool {
+    false
+}
@@ -0,0 +1,233 @@
+use clap::builder::TypedValueParser;
+use clap::error::ErrorKind;
+use clap::{Arg, Command};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Parses a path argument with `expand_path`, working on the raw argument so paths that are not
+/// valid UTF-8 are taken as they are.
+#[derive(Clone)]
+pub struct ExpandedPath;
+
+impl TypedValueParser for ExpandedPath {
+    type Value = PathBuf;
+
+    fn parse_ref(
+        &self,
+        cmd: &Command,
+        arg: Option<&Arg>,
+        value: &OsStr,
+    ) -> Result<PathBuf, clap::Error> {
+        expand_path(value).map_err(|message| {
+            let arg = arg.map_or_else(|| "...".to_string(), |arg| arg.to_string());
+            clap::Error::raw(
+                ErrorKind::ValueValidation,
+                format!(
+                    "invalid value '{}' for '{arg}': {message}\n",
+                    value.to_string_lossy()
+                ),
+            )
+            .with_cmd(cmd)
+        })
+    }
+}
+
+/// Expands a leading `~` to the home directory and `$VAR` or `${VAR}` to the variable's value, the
+/// way a shell would had the path not been quoted. Referencing an unset variable is an error
+/// rather than silently collapsing to an empty string. Bytes that are not UTF-8 pass through
+/// untouched, as do variable values.
+pub fn expand_path(path: &OsStr) -> Result<PathBuf, String> {
+    let mut expanded = OsString::new();
+    let mut rest = path.as_encoded_bytes();
+
+    if let Some(after_tilde) = rest.strip_prefix(b"~") {
+        if after_tilde.is_empty() || after_tilde.starts_with(b"/") {
+            expanded.push(env_var("HOME")?);
+            rest = after_tilde;
+        }
+    }
+
+    while let Some(dollar) = rest.iter().position(|&byte| byte == b'$') {
+        expanded.push(os_str(&rest[..dollar]));
+        let after_dollar = &rest[dollar + 1..];
+        let (name, remainder) = match after_dollar.strip_prefix(b"{") {
+            Some(braced) => {
+                let end = braced
+                    .iter()
+                    .position(|&byte| byte == b'}')
+                    .ok_or_else(|| format!("unterminated ${{ in {}", path.to_string_lossy()))?;
+                (&braced[..end], &braced[end + 1..])
+            }
+            None => {
+                let end = after_dollar
+                    .iter()
+                    .position(|&byte| !(byte.is_ascii_alphanumeric() || byte == b'_'))
+                    .unwrap_or(after_dollar.len());
+                after_dollar.split_at(end)
+            }
+        };
+
+        if name.is_empty() {
+            // A lone `$` is just a character.
+            expanded.push("$");
+            rest = after_dollar;
+        } else {
+            expanded.push(env_var(&String::from_utf8_lossy(name))?);
+            rest = remainder;
+        }
+    }
+    expanded.push(os_str(rest));
+
+    Ok(PathBuf::from(expanded))
+}
+
+/// The piece of an argument between two ASCII delimiters.
+fn os_str(bytes: &[u8]) -> &OsStr {
+    // SAFETY: `bytes` always comes from `OsStr::as_encoded_bytes` and is only ever split right
+    // before or after an ASCII character, which keeps each piece valid.
+    unsafe { OsStr::from_encoded_bytes_unchecked(bytes) }
+}
+
+/// The path whose `as_encoded_bytes` were stored on disk. Unix takes any bytes as they are;
+/// elsewhere they must be UTF-8, and `None` is returned for anything else.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Some(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    std::str::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Writes a path's encoded bytes as text that `unescape` turns back into the same bytes, for
+/// files like the plan and the manifest that only hold UTF-8. `%` and every byte that is not part
+/// of valid UTF-8 become `%XX`, everything else is kept as it is.
+pub fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for character in chunk.valid().chars() {
+            match character {
+                '%' => escaped.push_str("%25"),
+                character => escaped.push(character),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+/// The bytes `escape` was given, `None` if `escaped` holds a `%` not followed by two hex digits.
+pub fn unescape(escaped: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = after;
+            continue;
+        }
+        let hex = after
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+        bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+        rest = &after[2..];
+    }
+    Some(bytes)
+}
+
+fn env_var(name: &str) -> Result<OsString, String> {
+    env::var_os(name).ok_or_else(|| format!("environment variable {name} is not set"))
+}
+
+/// Parses a `--device-id`, which becomes a single folder name on the network so it must not be
+/// able to point outside the device's own namespace.
+pub fn parse_device_id(value: &str) -> Result<String, String> {
+    let valid = !value.is_empty()
+        && value != "."
+        && value != ".."
+        && !value.contains(['/', '\\'])
+        && !value.chars().any(char::is_control);
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!("{value:?} is not usable as a folder name"))
+    }
+}
+
+/// Where a device keeps its files on the network: its own `<device-id>/` folder below the shared
+/// network emulation directory, or the directory itself when no device id is configured.
+pub fn device_root(network_root: &Path, device_id: Option<&str>) -> PathBuf {
+    match device_id {
+        Some(device_id) => network_root.join(device_id),
+        None => network_root.to_path_buf(),
+    }
+}
+
+/// Warns once at startup if `--one-file-system` was asked for on a platform without device IDs.
+pub fn check_one_file_system_supported(one_file_system: bool) {
+    if cfg!(not(unix)) && one_file_system {
+        warn!("--one-file-system is only supported on Unix, ignoring");
+    }
+}
+
+/// Whether the directory described by `metadata`, met while walking `root`, is on another
+/// filesystem than `root`, for `--one-file-system`. Never on platforms without device IDs.
+#[cfg(unix)]
+pub fn crosses_file_system(root: &Path, metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata(root).is_ok_and(|root_metadata| root_metadata.dev() != metadata.dev())
+}
+
+#[cfg(not(unix))]
+pub fn crosses_file_system(_root: &Path, _metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Calls `visit` with the path relative to `root` and the metadata of every regular file below
+/// `relative`, in name order, leaving out `ignored`. A folder or entry below `root` that cannot be
+/// read goes to `unreadable` with the error, which decides whether the walk carries on.
+pub fn walk_files<E: From<io::Error>>(
+    root: &Path,
+    relative: &Path,
+    ignored: &[PathBuf],
+    visit: &mut impl FnMut(&Path, &Metadata) -> Result<(), E>,
+    unreadable: &mut impl FnMut(&Path, io::Error) -> Result<(), E>,
+) -> Result<(), E> {
+    let entries = fs::read_dir(root.join(relative))
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        // An unreadable root is no walk at all.
+        Err(e) if relative.as_os_str().is_empty() => return Err(e.into()),
+        Err(e) => return unreadable(relative, e),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        if ignored.iter().any(|ignored| relative.starts_with(ignored)) {
+            continue;
+        }
+
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                walk_files(root, &relative, ignored, visit, unreadable)?
+            }
+            Ok(metadata) if metadata.is_file() => visit(&relative, &metadata)?,
+            Ok(_) => {}
+            Err(e) => unreadable(&relative, e)?,
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use crate::{manifest, paths};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Bump whenever the plan file layout changes, so an old plan is refused instead of misread.
+pub const PLAN_VERSION: u32 = 3;
+
+/// What a sync would do, written by `--plan` and carried out by `--apply`. Paths are relative to
+/// the roots it was made for, so it can be reviewed and edited by hand. They are written with
+/// `paths::escape`, so a name that is not UTF-8 survives the round trip and a `%` in a name reads
+/// `%25`.
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    /// When it was made, in seconds since the Unix epoch.
+    pub created_at: u64,
+    #[serde(with = "escaped")]
+    pub local_root: PathBuf,
+    #[serde(with = "escaped")]
+    pub network_root: PathBuf,
+    /// Renames are applied first, then copies, then deletes, whatever order they are listed in.
+    pub actions: Vec<Action>,
+}
+
+/// One step of a plan, with what the files looked like when it was made so that `--apply` can
+/// tell when they changed since.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Rename a network directory that was moved on the local side.
+    Rename {
+        #[serde(with = "escaped")]
+        from: PathBuf,
+        #[serde(with = "escaped")]
+        to: PathBuf,
+    },
+    /// Copy a local file, by its logical path, to where the layout puts it on the network.
+    /// `existing` is the network file it overwrites, `None` when there was none.
+    Copy {
+        #[serde(with = "escaped")]
+        relative: PathBuf,
+        #[serde(with = "escaped")]
+        destination: PathBuf,
+        size: u64,
+        mtime_ns: u64,
+        existing: Option<FileState>,
+    },
+    /// Delete a network file with no local counterpart.
+    Delete {
+        #[serde(with = "escaped")]
+        path: PathBuf,
+        size: u64,
+        mtime_ns: u64,
+    },
+}
+
+/// Size and mtime of a file, as a plan records them.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime_ns: u64,
+}
+
+impl Plan {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let plan: Plan = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if plan.version != PLAN_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "plan {} has version {}, expected {}",
+                    path.display(),
+                    plan.version,
+                    PLAN_VERSION
+                ),
+            ));
+        }
+        for action in &plan.actions {
+            let paths = match action {
+                Action::Rename { from, to } => vec![from, to],
+                Action::Copy {
+                    relative,
+                    destination,
+                    ..
+                } => vec![relative, destination],
+                Action::Delete { path, .. } => vec![path],
+            };
+            if let Some(outside) = paths.into_iter().find(|path| !within_root(path)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "plan {} names {}, which is not a path below the emulation directory",
+                        path.display(),
+                        outside.display()
+                    ),
+                ));
+            }
+        }
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut contents = serde_json::to_vec_pretty(self)?;
+        contents.push(b'\n');
+        fs::write(path, contents)
+    }
+}
+
+/// Reads and writes a plan's paths with `paths::escape`.
+mod escaped {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&paths::escape(path.as_os_str().as_encoded_bytes()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        let escaped = String::deserialize(deserializer)?;
+        paths::unescape(&escaped)
+            .and_then(|bytes| paths::path_from_bytes(&bytes))
+            .ok_or_else(|| {
+                serde::de::Error::custom(format!("{escaped} is not a path on this platform"))
+            })
+    }
+}
+
+/// Whether `path` is relative and stays below the root it is joined to, which a hand-edited plan
+/// might not.
+fn within_root(path: &Path) -> bool {
+    !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Size and mtime of the file at `path`, as a plan records them.
+pub fn file_state(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), manifest::mtime_ns(&metadata)))
+}
+
+/// The state of the file at `path`, `None` when there is none.
+pub fn existing_state(path: &Path) -> io::Result<Option<FileState>> {
+    match file_state(path) {
+        Ok((size, mtime_ns)) => Ok(Some(FileState { size, mtime_ns })),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the file at `path` is still as the plan recorded it.
+pub fn unchanged(path: &Path, size: u64, mtime_ns: u64) -> bool {
+    file_state(path).is_ok_and(|state| state == (size, mtime_ns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_are_not_utf8_survive_a_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let relative = PathBuf::from(OsStr::from_bytes(b"saves/50%\xff.srm"));
+        let plan = Plan {
+            version: PLAN_VERSION,
+            created_at: 0,
+            local_root: dir.path().join("local"),
+            network_root: dir.path().join("network"),
+            actions: vec![Action::Copy {
+                relative: relative.clone(),
+                destination: relative.clone(),
+                size: 1,
+                mtime_ns: 2,
+                existing: None,
+            }],
+        };
+        let path = dir.path().join("plan.json");
+        plan.save(&path).unwrap();
+
+        let loaded = Plan::load(&path).unwrap();
+        match loaded.actions.as_slice() {
+            [Action::Copy {
+                relative: loaded_relative,
+                destination,
+                ..
+            }] => {
+                assert_eq!(loaded_relative, &relative);
+                assert_eq!(destination, &relative);
+            }
+            _ => panic!("expected a single copy"),
+        }
+        assert_eq!(loaded.local_root, plan.local_root);
+    }
+}
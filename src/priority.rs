@@ -0,0 +1,87 @@
+use clap::ValueEnum;
+use tracing::{info, warn};
+
+/// The Linux I/O scheduling class, as `ionice` names them.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoClass {
+    /// Only gets disk time when no other process wants it
+    Idle,
+    /// Shares disk time with other processes by priority, the default for every process
+    BestEffort,
+}
+
+/// How much CPU and disk time the sync gets next to the emulator in the foreground.
+pub struct Priority {
+    /// Scheduling niceness, from -20 (most favourable) to 19.
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+    /// Priority within `--ioclass best-effort`, from 0 (highest) to 7.
+    pub io_priority: u8,
+}
+
+/// Applies `priority` to this process. Threads started afterwards, such as the watcher's, inherit
+/// it, so this runs before the watch starts. Failures are only warned about.
+pub fn apply(priority: &Priority) {
+    if let Some(nice) = priority.nice {
+        match set_nice(nice) {
+            Ok(()) => info!("running at nice level {}", nice),
+            Err(e) => warn!("could not set nice level {}: {}", nice, e),
+        }
+    }
+    if let Some(class) = priority.io_class {
+        match set_io_priority(class, priority.io_priority) {
+            Ok(()) => info!("running in I/O class {:?}", class),
+            Err(e) => warn!("could not set I/O class {:?}: {}", class, e),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> std::io::Result<()> {
+    // SAFETY: setpriority only changes the scheduling priority of this process, `who` 0 being
+    // the caller.
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--nice is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(class: IoClass, level: u8) -> std::io::Result<()> {
+    // From linux/ioprio.h, which libc does not expose.
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+    let value = match class {
+        IoClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        IoClass::BestEffort => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level),
+    };
+    // SAFETY: ioprio_set takes three integers and only changes the I/O priority of this process,
+    // `who` 0 being the caller.
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_class: IoClass, _level: u8) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--ioclass is only supported on Linux",
+    ))
+}
@@ -0,0 +1,229 @@
+use crate::config;
+use crate::transform::Transform;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// The transform's name in the config file's `transforms` list.
+pub const NAME: &str = "retroarch";
+
+/// What the transform covers when the config file names no patterns: the configs and playlists
+/// below a RetroArch folder.
+pub const DEFAULT_FILES: [&str; 2] = ["**/retroarch/**/*.cfg", "**/retroarch/**/*.lpl"];
+
+/// Playlist fields that hold paths, in the JSON format RetroArch has written since 1.7.6.
+const PLAYLIST_PATH_FIELDS: [&str; 6] = [
+    "path",
+    "core_path",
+    "default_core_path",
+    "base_content_directory",
+    "scan_content_dir",
+    "scan_dat_file_path",
+];
+
+/// The config file's `[retroarch]` table.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetroArchConfig {
+    /// Path prefixes as this device's RetroArch sees them, mapped to the ones the network copies
+    /// use.
+    #[serde(default)]
+    pub path_prefixes: BTreeMap<String, String>,
+    /// Glob patterns, relative to the emulation root, of the playlists and configs rewritten.
+    #[serde(default, deserialize_with = "config::glob_list")]
+    pub files: Vec<String>,
+}
+
+/// The `retroarch` transform: rewrites the absolute paths in RetroArch playlists (`.lpl`) and
+/// configs (`.cfg`) matching the configured patterns from this device's prefixes to the network's
+/// on the way there, and back on the way here, so they work on both devices.
+pub struct PathRewriter {
+    patterns: Vec<Pattern>,
+    /// Local prefix to network prefix, longest local prefix first.
+    to_network: Vec<(String, String)>,
+    /// Network prefix to local prefix, longest network prefix first.
+    to_local: Vec<(String, String)>,
+}
+
+impl PathRewriter {
+    pub fn new(config: &RetroArchConfig) -> Result<Self, String> {
+        if config.path_prefixes.is_empty() {
+            return Err(format!(
+                "the {NAME} transform needs prefixes in the config file's [retroarch.path_prefixes]"
+            ));
+        }
+        let mut to_network: Vec<(String, String)> = config
+            .path_prefixes
+            .iter()
+            .map(|(local, network)| (trim_separator(local), trim_separator(network)))
+            .collect();
+        let mut to_local: Vec<(String, String)> = to_network
+            .iter()
+            .map(|(local, network)| (network.clone(), local.clone()))
+            .collect();
+        to_network.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        to_local.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        Ok(PathRewriter {
+            patterns: config::parse_globs(&config.files, &DEFAULT_FILES, "retroarch.files")?,
+            to_network,
+            to_local,
+        })
+    }
+
+    fn rewrite(
+        &self,
+        path: &Path,
+        mut reader: Box<dyn Read>,
+        prefixes: &[(String, String)],
+    ) -> io::Result<(PathBuf, Box<dyn Read>)> {
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+        let rewritten = match String::from_utf8(contents) {
+            Ok(text) => match file_kind(path) {
+                Some(FileKind::Playlist) => rewrite_playlist(path, &text, prefixes),
+                _ => rewrite_config(&text, prefixes),
+            },
+            Err(e) => {
+                warn!("{} is not UTF-8, copying it as it is", path.display());
+                e.into_bytes()
+            }
+        };
+        Ok((path.to_path_buf(), Box::new(Cursor::new(rewritten))))
+    }
+}
+
+impl Transform for PathRewriter {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn covers(&self, path: &Path) -> bool {
+        file_kind(path).is_some()
+            && self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.matches_path(path))
+    }
+
+    fn apply(&self, path: &Path, reader: Box<dyn Read>) -> io::Result<(PathBuf, Box<dyn Read>)> {
+        self.rewrite(path, reader, &self.to_network)
+    }
+
+    /// Rewriting a file whose paths are local already changes nothing, so `stored_without` is
+    /// not needed.
+    fn reverse(
+        &self,
+        path: &Path,
+        reader: Box<dyn Read>,
+        _stored_without: bool,
+    ) -> io::Result<(PathBuf, Box<dyn Read>)> {
+        self.rewrite(path, reader, &self.to_local)
+    }
+}
+
+enum FileKind {
+    Playlist,
+    Config,
+}
+
+fn file_kind(path: &Path) -> Option<FileKind> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "lpl" => Some(FileKind::Playlist),
+        "cfg" => Some(FileKind::Config),
+        _ => None,
+    }
+}
+
+/// Rewrites the path fields of a JSON playlist. Playlists older than RetroArch 1.7.6 are plain
+/// lines, a path on every other one, and get the same treatment as configs.
+fn rewrite_playlist(path: &Path, text: &str, prefixes: &[(String, String)]) -> Vec<u8> {
+    let Ok(mut playlist) = serde_json::from_str::<Value>(text) else {
+        return rewrite_config(text, prefixes);
+    };
+    rewrite_fields(&mut playlist, prefixes);
+    match serde_json::to_vec_pretty(&playlist) {
+        Ok(mut rewritten) => {
+            rewritten.push(b'\n');
+            rewritten
+        }
+        Err(e) => {
+            warn!("could not rewrite {}: {:?}", path.display(), e);
+            text.as_bytes().to_vec()
+        }
+    }
+}
+
+fn rewrite_fields(value: &mut Value, prefixes: &[(String, String)]) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                match field {
+                    Value::String(text) if PLAYLIST_PATH_FIELDS.contains(&key.as_str()) => {
+                        if let Some(rewritten) = rewrite_path(text, prefixes) {
+                            *text = rewritten;
+                        }
+                    }
+                    _ => rewrite_fields(field, prefixes),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rewrite_fields(item, prefixes);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rewrites the values of `key = "value"` lines, and whole lines that are a path, leaving
+/// everything else, comments, spacing and line endings included, as it was.
+fn rewrite_config(text: &str, prefixes: &[(String, String)]) -> Vec<u8> {
+    let mut rewritten = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let (content, ending) = split_line_ending(line);
+        let replaced = match content.split_once('=') {
+            Some((key, value)) => {
+                let quoted = value.trim();
+                let leading = &value[..value.len() - value.trim_start().len()];
+                let trailing = &value[value.trim_end().len()..];
+                quoted
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .and_then(|value| rewrite_path(value, prefixes))
+                    .map(|value| format!("{key}={leading}\"{value}\"{trailing}"))
+            }
+            None => rewrite_path(content, prefixes),
+        };
+        rewritten.push_str(replaced.as_deref().unwrap_or(content));
+        rewritten.push_str(ending);
+    }
+    rewritten.into_bytes()
+}
+
+fn split_line_ending(line: &str) -> (&str, &str) {
+    let content = line.trim_end_matches(['\n', '\r']);
+    (content, &line[content.len()..])
+}
+
+/// `path` with its prefix swapped, if it starts with one of `prefixes` at a path boundary.
+fn rewrite_path(path: &str, prefixes: &[(String, String)]) -> Option<String> {
+    prefixes.iter().find_map(|(from, to)| {
+        let rest = path.strip_prefix(from.as_str())?;
+        (rest.is_empty() || rest.starts_with(['/', '\\'])).then(|| format!("{to}{rest}"))
+    })
+}
+
+fn trim_separator(prefix: &str) -> String {
+    let trimmed = prefix.trim_end_matches(['/', '\\']);
+    if trimmed.is_empty() {
+        prefix.to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
@@ -0,0 +1,64 @@
+use futures::channel::oneshot;
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use std::future::Future;
+use std::panic;
+use std::thread;
+use tracing::info;
+
+/// Drives the daemon's long-running tasks, such as the network watcher, together on the calling
+/// thread until they have all finished or one of them fails. Tasks share that thread, so work
+/// that blocks, like copying a batch, goes through `blocking` to keep the others going.
+pub struct Runtime<'a, E> {
+    tasks: Vec<(&'static str, LocalBoxFuture<'a, Result<(), E>>)>,
+}
+
+impl<E> Default for Runtime<'_, E> {
+    fn default() -> Self {
+        Runtime { tasks: Vec::new() }
+    }
+}
+
+impl<'a, E> Runtime<'a, E> {
+    /// Adds a task, `name` saying what it is in the logs.
+    pub fn spawn(&mut self, name: &'static str, task: impl Future<Output = Result<(), E>> + 'a) {
+        self.tasks.push((name, task.boxed_local()));
+    }
+
+    /// Runs every task to completion. The first failure stops the others and is returned.
+    pub fn run(self) -> Result<(), E> {
+        let several = self.tasks.len() > 1;
+        let mut running: FuturesUnordered<_> = self
+            .tasks
+            .into_iter()
+            .map(|(name, task)| task.map(move |result| (name, result)))
+            .collect();
+        futures::executor::block_on(async {
+            while let Some((name, result)) = running.next().await {
+                result?;
+                if several {
+                    info!("{} finished", name);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Runs `work` on a thread of its own and waits for it without holding up the runtime's other
+/// tasks. A panic in `work` is carried over to the task that awaits it.
+pub async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    let (sender, receiver) = oneshot::channel();
+    let worker = thread::spawn(move || {
+        let _ = sender.send(work());
+    });
+    match receiver.await {
+        Ok(result) => result,
+        // The sender only goes away unsent when `work` panicked.
+        Err(oneshot::Canceled) => match worker.join() {
+            Err(panicked) => panic::resume_unwind(panicked),
+            Ok(()) => unreachable!("the blocking work finished without a result"),
+        },
+    }
+}
@@ -0,0 +1,304 @@
+use crate::copy::{self, CopyOptions};
+use crate::delete::Deleter;
+use crate::filter::PathFilter;
+use crate::paths;
+use crate::sync;
+use crate::telemetry::{ERRORS, EVENTS};
+use crate::units::{self, format_bytes, format_timestamp};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{error, info, warn};
+
+/// Suffix of a snapshot that is still being written. Never used as the previous snapshot, and
+/// removed by the next run if it was left behind.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+pub struct SnapshotOptions {
+    pub directory: PathBuf,
+    /// How many complete snapshots to keep, 0 keeps them all.
+    pub keep: usize,
+    /// Leave out directories on another filesystem than the source root.
+    pub one_file_system: bool,
+    /// Abort at the first folder that cannot be read, instead of leaving it out of the snapshot.
+    pub strict: bool,
+    /// `--include-regex` and `--exclude-regex`, so a snapshot holds what the sync would copy.
+    pub filter: PathFilter,
+}
+
+#[derive(Default)]
+pub struct SnapshotStats {
+    pub files_linked: usize,
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    pub files_failed: usize,
+    /// Folders left out as they could not be read.
+    pub folders_skipped: usize,
+}
+
+/// Writes a point-in-time copy of `source` into a new timestamped directory under the snapshot
+/// directory. Files whose size and mtime match the previous snapshot are hard linked to it, so an
+/// unchanged file costs no space, and only changed files are copied.
+pub fn create_snapshot(
+    source: &Path,
+    options: &SnapshotOptions,
+    copy_options: &CopyOptions,
+    deleter: &mut Deleter,
+) -> io::Result<SnapshotStats> {
+    copy::create_dir_all(&options.directory, copy_options)?;
+    remove_partial_snapshots(&options.directory);
+    check_hard_links_supported(&options.directory)?;
+
+    let previous = complete_snapshots(&options.directory)?.pop();
+    let name = format_timestamp(SystemTime::now());
+    let partial = options.directory.join(format!("{name}{PARTIAL_SUFFIX}"));
+    info!(
+        "creating snapshot {} against {}",
+        name,
+        previous
+            .as_ref()
+            .map_or("nothing".into(), |previous| previous.display().to_string())
+    );
+
+    let mut stats = SnapshotStats::default();
+    copy::create_dir_all(&partial, copy_options)?;
+    snapshot_directory(
+        source,
+        &partial,
+        previous.as_deref(),
+        Path::new(""),
+        options,
+        copy_options,
+        &mut stats,
+    )?;
+    fs::rename(&partial, options.directory.join(&name))?;
+
+    info!(target: EVENTS,
+        "snapshot {} finished: {} files linked, {} files copied ({}), {} failed, {} folders skipped",
+        name,
+        stats.files_linked,
+        stats.files_copied,
+        format_bytes(stats.bytes_copied),
+        stats.files_failed,
+        stats.folders_skipped
+    );
+
+    prune_snapshots(&options.directory, options.keep, deleter)?;
+    Ok(stats)
+}
+
+fn snapshot_directory(
+    source: &Path,
+    snapshot: &Path,
+    previous: Option<&Path>,
+    relative: &Path,
+    options: &SnapshotOptions,
+    copy_options: &CopyOptions,
+    stats: &mut SnapshotStats,
+) -> io::Result<()> {
+    let entries = fs::read_dir(source.join(relative))
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        // An unreadable root is no snapshot at all.
+        Err(e) if relative.as_os_str().is_empty() => return Err(e),
+        Err(e) => {
+            sync::skip_unreadable(options.strict, relative, e)?;
+            stats.folders_skipped += 1;
+            return Ok(());
+        }
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        let source_path = entry.path();
+
+        if source_path.is_dir() {
+            if options.one_file_system
+                && fs::metadata(&source_path)
+                    .is_ok_and(|metadata| paths::crosses_file_system(source, &metadata))
+            {
+                continue;
+            }
+            copy::create_dir_all(&snapshot.join(&relative), copy_options)?;
+            snapshot_directory(
+                source,
+                snapshot,
+                previous,
+                &relative,
+                options,
+                copy_options,
+                stats,
+            )?;
+            continue;
+        }
+
+        if !options.filter.allows(&relative) {
+            continue;
+        }
+        let previous_path = previous.map(|previous| previous.join(&relative));
+        if let Err(e) = snapshot_file(
+            &source_path,
+            &snapshot.join(&relative),
+            &relative,
+            previous_path.as_deref(),
+            copy_options,
+            stats,
+        ) {
+            error!(target: ERRORS, "could not snapshot {}: {:?}", source_path.display(), e);
+            stats.files_failed += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn snapshot_file(
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+    previous: Option<&Path>,
+    copy_options: &CopyOptions,
+    stats: &mut SnapshotStats,
+) -> io::Result<()> {
+    let source_metadata = fs::metadata(source)?;
+    let modified = source_metadata.modified()?;
+    // Snapshots live on the network, so they go through the transforms like the files they copy,
+    // and a transformed or snapshotted file's size says nothing about the source's. Only its mtime
+    // is compared.
+    let transformed = copy_options.stores_differently(relative);
+
+    let unchanged = previous
+        .and_then(|previous| fs::metadata(previous).ok())
+        .is_some_and(|previous_metadata| {
+            previous_metadata.is_file()
+                && (transformed || previous_metadata.len() == source_metadata.len())
+                && previous_metadata.modified().ok() == Some(modified)
+        });
+    if let (true, Some(previous)) = (unchanged, previous) {
+        fs::hard_link(previous, destination)?;
+        stats.files_linked += 1;
+        return Ok(());
+    }
+
+    copy::copy_to_network(
+        source,
+        destination,
+        relative,
+        copy_options,
+        false,
+        &mut |_| {},
+    )?;
+    // Snapshot files carry the source mtime, which is what the next run compares against.
+    File::options()
+        .write(true)
+        .open(destination)?
+        .set_modified(modified)?;
+    stats.files_copied += 1;
+    stats.bytes_copied += source_metadata.len();
+    Ok(())
+}
+
+/// Snapshots are useless without hard links, so find out before copying anything.
+fn check_hard_links_supported(directory: &Path) -> io::Result<()> {
+    let probe = directory.join(".emudeck_sync_link_probe");
+    let link = directory.join(".emudeck_sync_link_probe.link");
+    let _ = fs::remove_file(&link);
+    File::create(&probe)?;
+    let linked = fs::hard_link(&probe, &link);
+    let _ = fs::remove_file(&link);
+    let _ = fs::remove_file(&probe);
+
+    linked.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "snapshot directory {} does not support hard links, which --snapshot needs: {}",
+                directory.display(),
+                e
+            ),
+        )
+    })
+}
+
+/// Complete snapshots, oldest first. Timestamped names sort chronologically, and anything else
+/// in the directory is not a snapshot and left alone.
+fn complete_snapshots(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(directory)?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .filter(|entry| entry.file_name().to_str().is_some_and(units::is_timestamp))
+        .map(|entry| entry.path())
+        .collect();
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+fn remove_partial_snapshots(directory: &Path) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix(PARTIAL_SUFFIX))
+            .is_some_and(units::is_timestamp)
+        {
+            info!("removing unfinished snapshot {}", entry.path().display());
+            if let Err(e) = fs::remove_dir_all(entry.path()) {
+                warn!(
+                    "could not remove unfinished snapshot {}: {:?}",
+                    entry.path().display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+fn prune_snapshots(directory: &Path, keep: usize, deleter: &mut Deleter) -> io::Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+
+    let snapshots = complete_snapshots(directory)?;
+    let excess = snapshots.len().saturating_sub(keep);
+    for snapshot in &snapshots[..excess] {
+        info!("pruning snapshot {}", snapshot.display());
+        deleter.delete_tree(snapshot);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delete::DeleteOptions;
+
+    /// Folders in the snapshot directory that the snapshots did not create are neither a base
+    /// for hard links nor pruned, even when they sort first.
+    #[test]
+    fn unrelated_folders_are_not_snapshots() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["0000 old backups", "20240101T000000Z", "20240102T000000Z"] {
+            fs::create_dir(directory.path().join(name)).unwrap();
+        }
+
+        assert_eq!(
+            complete_snapshots(directory.path()).unwrap(),
+            [
+                directory.path().join("20240101T000000Z"),
+                directory.path().join("20240102T000000Z"),
+            ]
+        );
+
+        let mut deleter = Deleter::new(&DeleteOptions::default(), directory.path());
+        prune_snapshots(directory.path(), 1, &mut deleter).unwrap();
+        assert!(directory.path().join("0000 old backups").is_dir());
+        assert!(!directory.path().join("20240101T000000Z").exists());
+        assert!(directory.path().join("20240102T000000Z").is_dir());
+    }
+}
@@ -0,0 +1,119 @@
+use glob::Pattern;
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// What `--sqlite-safe` covers when the config file names no patterns.
+pub const DEFAULT_PATTERNS: [&str; 2] = ["**/*.db", "**/*.sqlite"];
+
+/// Files SQLite keeps next to an open database. They are folded into the snapshot, and copying
+/// them as they are would pair a stale journal with it.
+const SIDECAR_SUFFIXES: [&str; 3] = ["-wal", "-shm", "-journal"];
+
+/// How long the snapshot waits for a writer to let go of the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const PAGES_PER_STEP: i32 = 256;
+const PAUSE_BETWEEN_STEPS: Duration = Duration::from_millis(10);
+
+/// Names the temporary snapshots of concurrent copies apart.
+static SNAPSHOTS_TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/// The databases `--sqlite-safe` copies through SQLite's backup API, so that one a frontend has
+/// open reaches the network consistent rather than torn.
+pub struct SqliteSafe {
+    patterns: Vec<Pattern>,
+}
+
+impl SqliteSafe {
+    pub fn new(patterns: Vec<Pattern>) -> Self {
+        SqliteSafe { patterns }
+    }
+
+    /// Whether the file at `relative` to the emulation root is a database to snapshot.
+    pub fn covers(&self, relative: &Path) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches_path(relative))
+    }
+
+    /// Whether `relative` is the journal or shared memory file of a covered database, which the
+    /// snapshot already includes.
+    pub fn is_sidecar(&self, relative: &Path) -> bool {
+        let Some(name) = relative.file_name().and_then(|name| name.to_str()) else {
+            return false;
+        };
+        SIDECAR_SUFFIXES.iter().any(|suffix| {
+            name.strip_suffix(suffix)
+                .is_some_and(|database| self.covers(&relative.with_file_name(database)))
+        })
+    }
+}
+
+/// Whether the database at `source` has a write-ahead log with changes not yet checkpointed into
+/// it, which the database file's size and mtime do not show.
+pub fn has_pending_log(source: &Path) -> bool {
+    let mut log = source.as_os_str().to_os_string();
+    log.push("-wal");
+    fs::metadata(log).is_ok_and(|metadata| metadata.len() > 0)
+}
+
+/// A consistent copy of a database in the temporary directory, removed when dropped.
+pub struct Snapshot {
+    path: PathBuf,
+}
+
+impl Snapshot {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Snapshots the database at `source` with the backup API, which copies it page by page and
+/// starts over if a writer changes it part way. Fails with `InvalidData` if `source` is not a
+/// SQLite database at all.
+pub fn snapshot(source: &Path) -> io::Result<Snapshot> {
+    let snapshot = Snapshot {
+        path: std::env::temp_dir().join(format!(
+            "emudeck_sync-{}-{}.sqlite",
+            std::process::id(),
+            SNAPSHOTS_TAKEN.fetch_add(1, Ordering::Relaxed)
+        )),
+    };
+
+    let database = Connection::open_with_flags(
+        source,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| sqlite_error(source, e))?;
+    database
+        .busy_timeout(BUSY_TIMEOUT)
+        .map_err(|e| sqlite_error(source, e))?;
+    let mut copy = Connection::open(snapshot.path()).map_err(|e| sqlite_error(source, e))?;
+    Backup::new(&database, &mut copy)
+        .and_then(|backup| backup.run_to_completion(PAGES_PER_STEP, PAUSE_BETWEEN_STEPS, None))
+        .map_err(|e| sqlite_error(source, e))?;
+    drop(copy);
+    fs::set_permissions(snapshot.path(), fs::metadata(source)?.permissions())?;
+    Ok(snapshot)
+}
+
+fn sqlite_error(path: &Path, e: rusqlite::Error) -> io::Error {
+    let kind = match e.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::NotADatabase) => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(
+        kind,
+        format!("could not snapshot database {}: {}", path.display(), e),
+    )
+}
@@ -0,0 +1,124 @@
+use crate::units::{format_bytes, format_duration};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Which way the last run copied.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// The initial sync, local to network.
+    Push,
+    /// A watcher batch, network to local.
+    Pull,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunStatus {
+    /// Seconds since the Unix epoch.
+    pub finished_at: u64,
+    pub direction: Direction,
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    pub files_failed: u64,
+    pub files_deleted: u64,
+}
+
+/// What the last process using a state directory did, kept in `status.json` there for status
+/// bars and scripts.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Status {
+    /// Stays true if the process was killed, so `pid` is worth checking.
+    pub watching: bool,
+    pub pid: Option<u32>,
+    pub last_run: Option<RunStatus>,
+}
+
+pub fn status_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("status.json")
+}
+
+/// Reads the status a run left in `state_dir`, `None` if nothing has run there yet.
+pub fn read(state_dir: &Path) -> io::Result<Option<Status>> {
+    match fs::read(status_path(state_dir)) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Records a finished sync or batch.
+pub fn record_run(state_dir: &Path, run: RunStatus) {
+    update(state_dir, |status| status.last_run = Some(run));
+}
+
+pub fn record_watching(state_dir: &Path, watching: bool) {
+    update(state_dir, |status| status.watching = watching);
+}
+
+/// Seconds since the Unix epoch, for `RunStatus::finished_at`.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Failing to write the status never stops a sync, it is only warned about.
+fn update(state_dir: &Path, change: impl FnOnce(&mut Status)) {
+    let path = status_path(state_dir);
+    let mut status = read(state_dir).ok().flatten().unwrap_or_default();
+    change(&mut status);
+    status.pid = Some(std::process::id());
+
+    let result = fs::create_dir_all(state_dir).and_then(|()| {
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&status)?)?;
+        fs::rename(&temporary, &path)
+    });
+    if let Err(e) = result {
+        warn!("could not write status {}: {:?}", path.display(), e);
+    }
+}
+
+pub fn print_status(status: Option<&Status>, json: bool) -> io::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let Some(status) = status else {
+        println!("never synced");
+        return Ok(());
+    };
+    match &status.last_run {
+        Some(run) => {
+            let ago = Duration::from_secs(now().saturating_sub(run.finished_at));
+            let direction = match run.direction {
+                Direction::Push => "local to network",
+                Direction::Pull => "network to local",
+            };
+            println!(
+                "last synced {} ago ({}): {} files ({}) copied, {} failed, {} deleted",
+                format_duration(ago),
+                direction,
+                run.files_copied,
+                format_bytes(run.bytes_copied),
+                run.files_failed,
+                run.files_deleted
+            );
+        }
+        None => println!("never synced"),
+    }
+    if status.watching {
+        match status.pid {
+            Some(pid) => println!("watching (pid {pid})"),
+            None => println!("watching"),
+        }
+    } else {
+        println!("not watching");
+    }
+    Ok(())
+}
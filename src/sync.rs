@@ -0,0 +1,1153 @@
+use crate::battery::BatteryThrottle;
+use crate::checksum_cache::ChecksumCache;
+use crate::compare::{self, CompareBy, HashCache};
+use crate::config::DEFAULT_FOLDER_ORDER;
+use crate::copy::{self, CopyOptions, TransferMethod};
+use crate::delete::{DeleteOptions, Deleter};
+use crate::filter::PathFilter;
+use crate::layout::Layout;
+use crate::manifest::{self, Manifest, ManifestEntry, Side, StoredCopy};
+use crate::moves;
+use crate::paths;
+use crate::plan::{self, Action};
+use crate::sqlite;
+use crate::telemetry::{ERRORS, EVENTS, PROGRESS};
+use crate::units::{format_bytes, format_duration};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::span::EnteredSpan;
+use tracing::{error, field, info, info_span, warn};
+
+pub struct SyncOptions {
+    pub copy: CopyOptions,
+    /// How files that already exist on the destination are judged up to date.
+    pub compare_by: CompareBy,
+    /// Confirm every copied file with SHA-256.
+    pub verify: bool,
+    /// Confirm matching fast hashes with SHA-256 too, for `--verify=all`.
+    pub verify_unchanged: bool,
+    /// Carry on with a warning instead of aborting when the destination looks too full.
+    pub ignore_space: bool,
+    /// Sync order of top-level folders, lower numbers are copied first.
+    pub folder_order: BTreeMap<String, i64>,
+    /// Abort once this many files failed to copy, 0 never aborts.
+    pub max_errors: usize,
+    /// Delete files on the destination that no longer exist on the source.
+    pub delete_extraneous: bool,
+    /// Rename destination directories that were moved on the source instead of copying them anew.
+    pub detect_moves: bool,
+    pub delete: DeleteOptions,
+    /// Paths relative to the destination root that are never treated as extraneous.
+    pub ignored: Vec<PathBuf>,
+    /// Pause folders ordered at or after the default while on a low battery.
+    pub battery: Option<BatteryThrottle>,
+    /// Abort at the first file that cannot be read or copied instead of skipping it.
+    pub strict: bool,
+    /// Create every source directory on the destination, including ones no file is copied into.
+    pub sync_empty_dirs: bool,
+    /// Where each source file goes below the destination.
+    pub layout: Layout,
+    /// Leave out directories on another filesystem than the source root.
+    pub one_file_system: bool,
+    /// `--include-regex` and `--exclude-regex`.
+    pub filter: PathFilter,
+    pub checksum_cache: Option<Arc<ChecksumCache>>,
+    /// Never overwrite a destination file that is newer than the source, for a source that may
+    /// be a stale snapshot.
+    pub source_not_authoritative: bool,
+    /// When `--time-limit` runs out, after which no further file is copied.
+    pub deadline: Option<Instant>,
+}
+
+/// What a sync run did.
+#[derive(Default)]
+pub struct SyncStats {
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    pub files_failed: usize,
+    /// Unreadable files and ones that are not regular files, left out of the sync.
+    pub files_skipped: usize,
+    /// Files the destination already had.
+    pub files_unchanged: usize,
+    /// Files left alone as the destination's copy is newer, for `--source-not-authoritative`.
+    pub files_newer_on_destination: usize,
+    /// How the copied files were transferred.
+    pub by_method: BTreeMap<TransferMethod, MethodStats>,
+    pub files_deleted: usize,
+    pub directories_moved: usize,
+    /// Empty directories created by `--sync-empty-dirs`.
+    pub directories_created: usize,
+    /// Actions `--apply` left out, as their files changed since the plan was made.
+    pub actions_stale: usize,
+    /// Time spent copying files.
+    pub copy_time: Duration,
+    /// Files still to copy when `--time-limit` ran out.
+    pub files_left: usize,
+}
+
+impl SyncStats {
+    /// Whether the run copied, deleted, moved, created or failed anything, or left files over.
+    pub fn changed_anything(&self) -> bool {
+        self.files_copied
+            + self.files_failed
+            + self.files_deleted
+            + self.directories_moved
+            + self.directories_created
+            + self.files_left
+            > 0
+    }
+}
+
+/// Files and bytes transferred by one `TransferMethod`.
+#[derive(Default, Clone, Copy)]
+pub struct MethodStats {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    Io(io::Error),
+    /// `--max-errors` copies failed, which points at a systemic fault rather than bad files.
+    TooManyErrors(usize),
+}
+
+impl From<io::Error> for SyncError {
+    fn from(e: io::Error) -> Self {
+        SyncError::Io(e)
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Io(e) => write!(f, "{e}"),
+            SyncError::TooManyErrors(failed) => {
+                write!(f, "aborted after {failed} files failed to copy")
+            }
+        }
+    }
+}
+
+/// A file that the walk decided has to be copied.
+struct FileJob {
+    source: PathBuf,
+    destination: PathBuf,
+    relative: PathBuf,
+    size: u64,
+}
+
+/// How often the transfer rate is sampled.
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time constant of the transfer rate's moving average: a sample this old counts for about a
+/// third of one just taken, so a slowdown shows in the estimate within seconds.
+const RATE_TIME_CONSTANT: Duration = Duration::from_secs(10);
+
+/// Progress is also logged this often, for large files that take long to cross a tenth.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Logs copy progress roughly every 10 percent of the total bytes, with the transfer rate and
+/// the time left at that rate.
+struct Progress {
+    total_bytes: u64,
+    copied_bytes: u64,
+    last_logged_decile: Option<u64>,
+    started: Instant,
+    last_logged: Instant,
+    sampled: Instant,
+    sampled_bytes: u64,
+    /// Exponential moving average of bytes per second, once a sample was taken.
+    rate: Option<f64>,
+}
+
+impl Progress {
+    fn new(total_bytes: u64) -> Self {
+        let now = Instant::now();
+        Progress {
+            total_bytes,
+            copied_bytes: 0,
+            last_logged_decile: None,
+            started: now,
+            last_logged: now,
+            sampled: now,
+            sampled_bytes: 0,
+            rate: None,
+        }
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.copied_bytes += bytes;
+        let now = Instant::now();
+        self.sample_rate(now);
+
+        let percentage = if self.total_bytes == 0 {
+            100.0
+        } else {
+            (self.copied_bytes as f64 / self.total_bytes as f64) * 100.0
+        };
+
+        let decile = percentage as u64 / 10;
+        if self.last_logged_decile != Some(decile)
+            || now.duration_since(self.last_logged) >= PROGRESS_LOG_INTERVAL
+        {
+            self.last_logged_decile = Some(decile);
+            self.last_logged = now;
+            match self.rate {
+                Some(rate) if self.copied_bytes < self.total_bytes => info!(target: PROGRESS,
+                    "emulation folder synchronisation progress: {:.2}%, {}/s, about {} left",
+                    percentage,
+                    format_bytes(rate as u64),
+                    self.time_left(rate)
+                ),
+                _ => info!(target: PROGRESS,
+                    "emulation folder synchronisation progress: {:.2}%",
+                    percentage
+                ),
+            }
+        }
+    }
+
+    /// Folds the rate since the last sample into the moving average, weighted by how long ago
+    /// the last sample was.
+    fn sample_rate(&mut self, now: Instant) {
+        let interval = now.duration_since(self.sampled);
+        if interval < RATE_SAMPLE_INTERVAL {
+            return;
+        }
+        let current = (self.copied_bytes - self.sampled_bytes) as f64 / interval.as_secs_f64();
+        let weight = 1.0 - (-interval.as_secs_f64() / RATE_TIME_CONSTANT.as_secs_f64()).exp();
+        self.rate = Some(match self.rate {
+            Some(rate) => rate + weight * (current - rate),
+            None => current,
+        });
+        self.sampled = now;
+        self.sampled_bytes = self.copied_bytes;
+    }
+
+    fn time_left(&self, rate: f64) -> String {
+        if rate < 1.0 {
+            return "an unknown time".to_string();
+        }
+        let left = (self.total_bytes - self.copied_bytes) as f64 / rate;
+        format_duration(Duration::from_secs_f64(left.min(u32::MAX as f64)))
+    }
+}
+
+/// What a sync would transfer, worked out without copying anything.
+pub struct TransferEstimate {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+pub fn estimate_transfer(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    manifest: &mut Manifest,
+) -> io::Result<TransferEstimate> {
+    let jobs = plan_jobs(
+        options,
+        source,
+        destination,
+        manifest,
+        &mut SyncStats::default(),
+    )?;
+    Ok(TransferEstimate {
+        files: jobs.len(),
+        bytes: jobs.iter().map(|job| job.size).sum(),
+    })
+}
+
+pub fn sync_directories(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    manifest: &mut Manifest,
+) -> Result<SyncStats, SyncError> {
+    if !destination.exists() {
+        info!(
+            "network emulation directory: {} does not exist, creating",
+            destination.display()
+        );
+        copy::create_dir_all(destination, &options.copy)?;
+    }
+
+    let mut stats = SyncStats::default();
+    if options.detect_moves {
+        stats.directories_moved = moves::detect_moves(options, source, destination)?;
+    }
+
+    let jobs = plan_jobs(options, source, destination, manifest, &mut stats)?;
+    let total_bytes = jobs.iter().map(|job| job.size).sum();
+    info!(target: PROGRESS,
+        "{} files ({}) need copying",
+        jobs.len(),
+        format_bytes(total_bytes)
+    );
+    check_free_space(options, destination, total_bytes)?;
+
+    let mut progress = Progress::new(total_bytes);
+    let mut folder_span: Option<(Option<&OsStr>, EnteredSpan)> = None;
+    for (index, job) in jobs.iter().enumerate() {
+        // Jobs of one top-level folder are contiguous, so each folder gets a single span.
+        let folder = top_level_folder(&job.relative);
+        if folder_span.as_ref().map(|(current, _)| *current) != Some(folder) {
+            // Leave the previous folder's span before opening the next, or it becomes the parent.
+            drop(folder_span.take());
+            let span = info_span!(
+                "sync_folder",
+                folder = %folder.unwrap_or_default().to_string_lossy()
+            );
+            folder_span = Some((folder, span.entered()));
+        }
+
+        let mut out_of_time = past_deadline(options);
+        // Saves and the other small folders ordered ahead of the default are worth the battery,
+        // the bulk of the ROMs can wait for a charger.
+        if let Some(throttle) = &options.battery {
+            if !out_of_time && folder_order(options, &job.relative) >= DEFAULT_FOLDER_ORDER {
+                out_of_time = !throttle.wait_for_power(options.deadline);
+            }
+        }
+        if out_of_time {
+            stats.files_left = jobs.len() - index;
+            break;
+        }
+        run_job(options, job, manifest, &mut progress, &mut stats)?;
+    }
+    stats.copy_time = progress.started.elapsed();
+
+    if stats.files_left > 0 {
+        warn_files_left(&jobs[jobs.len() - stats.files_left..]);
+    } else {
+        if options.sync_empty_dirs {
+            stats.directories_created =
+                create_directories(options, source, destination, Path::new(""))?;
+        }
+
+        if options.delete_extraneous {
+            stats.files_deleted = delete_extraneous(options, source, destination)?;
+        }
+    }
+
+    info!(target: EVENTS,
+        "sync finished: {} files copied ({}) in {} at {}/s, {} failed, {} skipped, {} deleted, {} directories moved",
+        stats.files_copied,
+        format_bytes(stats.bytes_copied),
+        format_duration(stats.copy_time),
+        format_bytes(average_rate(stats.bytes_copied, stats.copy_time)),
+        stats.files_failed,
+        stats.files_skipped,
+        stats.files_deleted,
+        stats.directories_moved
+    );
+    log_transfer_methods(&stats);
+    Ok(stats)
+}
+
+/// Whether `--time-limit` ran out, after which no further file is started.
+pub fn past_deadline(options: &SyncOptions) -> bool {
+    options
+        .deadline
+        .is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Warns about the jobs the time limit left for the next run.
+fn warn_files_left(jobs: &[FileJob]) {
+    warn!(target: EVENTS,
+        "time limit reached, leaving {} files ({}) for the next run",
+        jobs.len(),
+        format_bytes(jobs.iter().map(|job| job.size).sum())
+    );
+}
+
+/// Bytes per second over `duration`.
+fn average_rate(bytes: u64, duration: Duration) -> u64 {
+    if duration.is_zero() {
+        return 0;
+    }
+    (bytes as f64 / duration.as_secs_f64()) as u64
+}
+
+/// Copies the `count` most recently modified files below `folders` that need copying, ahead of the
+/// full sync, so that the saves of a game just played reach the network within seconds.
+pub fn sync_recent(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    folders: &[String],
+    count: usize,
+    manifest: &mut Manifest,
+) -> Result<SyncStats, SyncError> {
+    if !destination.exists() {
+        copy::create_dir_all(destination, &options.copy)?;
+    }
+
+    let mut stats = SyncStats::default();
+    let mut jobs = Vec::new();
+    for folder in folders {
+        if source.join(folder).is_dir() {
+            collect_jobs(
+                options,
+                source,
+                destination,
+                Path::new(folder),
+                manifest,
+                &mut jobs,
+                &mut stats,
+            )?;
+        }
+    }
+    let mut jobs: Vec<(SystemTime, FileJob)> = jobs
+        .into_iter()
+        .filter_map(|job| {
+            let modified = fs::metadata(&job.source).and_then(|m| m.modified()).ok()?;
+            Some((modified, job))
+        })
+        .collect();
+    jobs.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    jobs.truncate(count);
+
+    let mut progress = Progress::new(jobs.iter().map(|(_, job)| job.size).sum());
+    for (index, (_, job)) in jobs.iter().enumerate() {
+        // The full sync after this one reports what is left.
+        if past_deadline(options) {
+            stats.files_left = jobs.len() - index;
+            break;
+        }
+        run_job(options, job, manifest, &mut progress, &mut stats)?;
+    }
+    info!(target: EVENTS,
+        "{} recent saves copied ({}) ahead of the full sync, {} failed",
+        stats.files_copied,
+        format_bytes(stats.bytes_copied),
+        stats.files_failed
+    );
+    Ok(stats)
+}
+
+/// Works out what `sync_directories` would rename, copy and delete, without changing anything.
+pub fn plan_sync(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    manifest: &mut Manifest,
+) -> io::Result<Vec<Action>> {
+    let moves = if options.detect_moves && destination.is_dir() {
+        moves::find_moves(options, source, destination)?
+    } else {
+        Vec::new()
+    };
+
+    let mut actions: Vec<Action> = moves
+        .iter()
+        .map(|(from, to)| Action::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        })
+        .collect();
+    let jobs = plan_jobs(
+        options,
+        source,
+        destination,
+        manifest,
+        &mut SyncStats::default(),
+    )?;
+    for job in jobs {
+        let target = job
+            .destination
+            .strip_prefix(destination)
+            .unwrap_or(&job.destination)
+            .to_path_buf();
+        // Below a directory the plan renames, the file is judged where it is until then.
+        let renamed = moves.iter().find_map(|(from, to)| {
+            let rest = target.strip_prefix(to).ok()?;
+            Some(destination.join(from).join(rest))
+        });
+        if let Some(current) = &renamed {
+            if !needs_copy(options, &job.source, current, &job.relative, manifest)? {
+                continue;
+            }
+        }
+        let (size, mtime_ns) = plan::file_state(&job.source)?;
+        let existing = plan::existing_state(renamed.as_ref().unwrap_or(&job.destination))?;
+        actions.push(Action::Copy {
+            relative: job.relative,
+            destination: target,
+            size,
+            mtime_ns,
+            existing,
+        });
+    }
+
+    if options.delete_extraneous && destination.is_dir() {
+        for files in find_extraneous(options, source, destination)?.into_values() {
+            for file in files {
+                let path = file.strip_prefix(destination).unwrap_or(&file);
+                // Renamed into place rather than deleted.
+                if moves.iter().any(|(from, _)| path.starts_with(from)) {
+                    continue;
+                }
+                let (size, mtime_ns) = plan::file_state(&file)?;
+                actions.push(Action::Delete {
+                    path: path.to_path_buf(),
+                    size,
+                    mtime_ns,
+                });
+            }
+        }
+    }
+    Ok(actions)
+}
+
+/// Carries out a plan from `plan_sync` without walking either tree: renames first, then copies,
+/// then deletes. Actions whose files changed since the plan was made are left out with a warning.
+pub fn apply_plan(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    actions: &[Action],
+    manifest: &mut Manifest,
+) -> Result<SyncStats, SyncError> {
+    if !destination.exists() {
+        copy::create_dir_all(destination, &options.copy)?;
+    }
+
+    let mut stats = SyncStats::default();
+    let stale = |stats: &mut SyncStats, path: &Path| {
+        warn!(target: EVENTS,
+            "{} changed since the plan was made, leaving it out",
+            path.display()
+        );
+        stats.actions_stale += 1;
+    };
+    let mut jobs = Vec::new();
+    let mut extraneous: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for action in actions {
+        match action {
+            Action::Rename { from, to } => {
+                if !destination.join(from).is_dir() || destination.join(to).exists() {
+                    stale(&mut stats, from);
+                } else if moves::apply_move(options, destination, from, to)? {
+                    stats.directories_moved += 1;
+                }
+            }
+            Action::Copy {
+                relative,
+                destination: target,
+                size,
+                mtime_ns,
+                existing,
+            } => {
+                let source_path = source.join(relative);
+                // After the renames above, so a file below a renamed directory is found.
+                let destination_path = destination.join(target);
+                if !plan::unchanged(&source_path, *size, *mtime_ns)
+                    || plan::existing_state(&destination_path).ok() != Some(*existing)
+                {
+                    stale(&mut stats, relative);
+                    continue;
+                }
+                jobs.push(FileJob {
+                    source: source_path,
+                    destination: destination_path,
+                    relative: relative.clone(),
+                    size: *size,
+                });
+            }
+            Action::Delete {
+                path,
+                size,
+                mtime_ns,
+            } => {
+                if !plan::unchanged(&destination.join(path), *size, *mtime_ns) {
+                    stale(&mut stats, path);
+                    continue;
+                }
+                extraneous
+                    .entry(path.parent().unwrap_or(Path::new("")).to_path_buf())
+                    .or_default()
+                    .push(destination.join(path));
+            }
+        }
+    }
+
+    let mut progress = Progress::new(jobs.iter().map(|job| job.size).sum());
+    for (index, job) in jobs.iter().enumerate() {
+        if past_deadline(options) {
+            stats.files_left = jobs.len() - index;
+            break;
+        }
+        run_job(options, job, manifest, &mut progress, &mut stats)?;
+    }
+    // Like a sync, a plan cut short by the time limit deletes nothing, the next one will.
+    if stats.files_left > 0 {
+        warn_files_left(&jobs[jobs.len() - stats.files_left..]);
+    } else {
+        stats.files_deleted = delete_found(options, source, destination, &extraneous);
+    }
+
+    info!(target: EVENTS,
+        "plan applied: {} files copied ({}), {} failed, {} deleted, {} directories moved, {} actions left out",
+        stats.files_copied,
+        format_bytes(stats.bytes_copied),
+        stats.files_failed,
+        stats.files_deleted,
+        stats.directories_moved,
+        stats.actions_stale
+    );
+    log_transfer_methods(&stats);
+    Ok(stats)
+}
+
+/// Copies one planned file and counts the outcome in `stats`.
+fn run_job(
+    options: &SyncOptions,
+    job: &FileJob,
+    manifest: &mut Manifest,
+    progress: &mut Progress,
+    stats: &mut SyncStats,
+) -> Result<(), SyncError> {
+    let file_span = info_span!(
+        "copy_file",
+        path = %job.relative.display(),
+        bytes = job.size,
+        outcome = field::Empty,
+        otel.status_code = field::Empty,
+    )
+    .entered();
+    match copy_job(options, job, manifest, progress) {
+        Ok(method) => {
+            file_span.record("outcome", "copied");
+            stats.files_copied += 1;
+            stats.bytes_copied += job.size;
+            let by_method = stats.by_method.entry(method).or_default();
+            by_method.files += 1;
+            by_method.bytes += job.size;
+        }
+        Err(e) => {
+            file_span.record("outcome", "failed");
+            file_span.record("otel.status_code", "ERROR");
+            error!(target: ERRORS, "could not copy {}: {:?}", job.source.display(), e);
+            if options.strict {
+                return Err(SyncError::Io(e));
+            }
+            stats.files_failed += 1;
+            if options.max_errors != 0 && stats.files_failed >= options.max_errors {
+                return Err(SyncError::TooManyErrors(stats.files_failed));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Creates the source directories still missing on the destination once the files are copied,
+/// which leaves only the empty ones. Returns how many were created.
+fn create_directories(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+) -> io::Result<usize> {
+    let mut created = 0;
+    let entries = match fs::read_dir(source.join(relative)) {
+        Ok(entries) => entries,
+        // Already counted as skipped when the files were planned.
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && !options.strict => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let relative = relative.join(entry.file_name());
+        if options.layout.flattens(&relative) {
+            continue;
+        }
+        if options.one_file_system
+            && fs::metadata(entry.path())
+                .is_ok_and(|metadata| paths::crosses_file_system(source, &metadata))
+        {
+            continue;
+        }
+        let target = destination.join(&relative);
+        if !target.is_dir() {
+            copy::create_dir_all(&target, &options.copy)?;
+            created += 1;
+        }
+        created += create_directories(options, source, destination, &relative)?;
+    }
+    Ok(created)
+}
+
+/// Deletes destination files that have no counterpart on the source, one batch per folder, then
+/// removes folders that emptied and no longer exist on the source either.
+fn delete_extraneous(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+) -> io::Result<usize> {
+    let extraneous = find_extraneous(options, source, destination)?;
+    Ok(delete_found(options, source, destination, &extraneous))
+}
+
+/// The destination files without a source counterpart, by the folder relative to `destination`
+/// they are in.
+fn find_extraneous(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+) -> io::Result<BTreeMap<PathBuf, Vec<PathBuf>>> {
+    let mut extraneous = BTreeMap::new();
+    // An empty source is far more likely an unmounted SD card than a deliberately emptied
+    // library, and mirroring it would wipe the backup.
+    if fs::read_dir(source)?.next().is_none() {
+        warn!(target: EVENTS,
+            "{} is empty, not deleting anything from {}",
+            source.display(),
+            destination.display()
+        );
+        return Ok(extraneous);
+    }
+    collect_extraneous(options, source, destination, Path::new(""), &mut extraneous)?;
+    Ok(extraneous)
+}
+
+/// Deletes `extraneous`, as `find_extraneous` collects it. Returns how many files were deleted.
+fn delete_found(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    extraneous: &BTreeMap<PathBuf, Vec<PathBuf>>,
+) -> usize {
+    let mut deleter = Deleter::new(&options.delete, destination);
+    let mut deleted = 0;
+    // Deepest folders first, so a parent is only considered once its children are gone.
+    for (folder, files) in extraneous.iter().rev() {
+        deleted += deleter.delete_files(&destination.join(folder), files);
+
+        let mut folder = folder.as_path();
+        while !folder.as_os_str().is_empty()
+            && !source.join(folder).exists()
+            && fs::remove_dir(destination.join(folder)).is_ok()
+        {
+            folder = folder.parent().unwrap_or(Path::new(""));
+        }
+    }
+    deleted
+}
+
+fn collect_extraneous(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+    extraneous: &mut BTreeMap<PathBuf, Vec<PathBuf>>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(destination.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let relative_path = relative.join(entry.file_name());
+        if options
+            .ignored
+            .iter()
+            .any(|ignored| relative_path.starts_with(ignored))
+        {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            collect_extraneous(options, source, destination, &relative_path, extraneous)?;
+        } else {
+            // A filtered out file is left alone on the destination.
+            let local_relative = options.layout.to_local(&relative_path);
+            if !options.filter.allows(&local_relative)
+                || fs::symlink_metadata(source.join(local_relative)).is_ok()
+            {
+                continue;
+            }
+            extraneous
+                .entry(relative.to_path_buf())
+                .or_default()
+                .push(entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+fn plan_jobs(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    manifest: &mut Manifest,
+    stats: &mut SyncStats,
+) -> io::Result<Vec<FileJob>> {
+    let mut jobs = Vec::new();
+    collect_jobs(
+        options,
+        source,
+        destination,
+        Path::new(""),
+        manifest,
+        &mut jobs,
+        stats,
+    )?;
+
+    // Stable, so files within a folder keep their walk order.
+    jobs.sort_by_key(|job| folder_order(options, &job.relative));
+    Ok(jobs)
+}
+
+fn folder_order(options: &SyncOptions, relative: &Path) -> i64 {
+    top_level_folder(relative)
+        .and_then(|folder| options.folder_order.get(folder.to_string_lossy().as_ref()))
+        .copied()
+        .unwrap_or(DEFAULT_FOLDER_ORDER)
+}
+
+/// The top-level folder a file lives in, `None` for files directly in the root.
+fn top_level_folder(relative: &Path) -> Option<&OsStr> {
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(folder)), Some(_)) => Some(folder),
+        _ => None,
+    }
+}
+
+/// Refuses to start a copy that cannot fit on the destination, rather than failing part way
+/// through with a half written backup. Overwritten files are counted at their full size, so the
+/// estimate errs on the safe side.
+fn check_free_space(options: &SyncOptions, destination: &Path, needed: u64) -> io::Result<()> {
+    let available = match fs2::available_space(destination) {
+        Ok(available) => available,
+        Err(e) => {
+            warn!(
+                "could not read the free space on {}, skipping the space check: {:?}",
+                destination.display(),
+                e
+            );
+            return Ok(());
+        }
+    };
+    if needed <= available {
+        return Ok(());
+    }
+
+    let message = format!(
+        "not enough space on {}: need {}, {} available, short by {}",
+        destination.display(),
+        format_bytes(needed),
+        format_bytes(available),
+        format_bytes(needed - available)
+    );
+    if options.ignore_space {
+        warn!("{message}, continuing because of --ignore-space");
+        return Ok(());
+    }
+    Err(io::Error::new(io::ErrorKind::StorageFull, message))
+}
+
+fn collect_jobs(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+    manifest: &mut Manifest,
+    jobs: &mut Vec<FileJob>,
+    stats: &mut SyncStats,
+) -> io::Result<()> {
+    let entries = fs::read_dir(source.join(relative))
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        // Only a folder below the root, an unreadable root is no sync at all.
+        Err(e) if !relative.as_os_str().is_empty() => {
+            return skip_or_abort(options, relative, e, stats)
+        }
+        Err(e) => return Err(e),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        let source_path = entry.path();
+        let destination_path = destination.join(options.layout.to_network(&relative));
+
+        let result = match fs::metadata(&source_path) {
+            Ok(metadata) if metadata.is_dir() => {
+                if options.one_file_system && paths::crosses_file_system(source, &metadata) {
+                    info!(
+                        "skipping {}, it is on another filesystem",
+                        source_path.display()
+                    );
+                    continue;
+                }
+                collect_jobs(
+                    options,
+                    source,
+                    destination,
+                    &relative,
+                    manifest,
+                    jobs,
+                    stats,
+                )?;
+                continue;
+            }
+            Ok(_) if !options.filter.allows(&relative) => continue,
+            // The database's snapshot already holds what its journal does.
+            Ok(_)
+                if options
+                    .copy
+                    .sqlite_safe
+                    .as_ref()
+                    .is_some_and(|sqlite_safe| sqlite_safe.is_sidecar(&relative)) =>
+            {
+                continue;
+            }
+            Ok(metadata) => copy::check_copyable(&metadata)
+                .and_then(|()| {
+                    needs_copy(
+                        options,
+                        &source_path,
+                        &destination_path,
+                        &relative,
+                        manifest,
+                    )
+                })
+                .map(|needed| {
+                    if needed
+                        && options.source_not_authoritative
+                        && newer_on_destination(&metadata, &destination_path)
+                    {
+                        info!(target: EVENTS,
+                            "{} is newer on the network, not overwriting it",
+                            relative.display()
+                        );
+                        stats.files_newer_on_destination += 1;
+                    } else if needed {
+                        jobs.push(FileJob {
+                            size: metadata.len(),
+                            source: source_path,
+                            destination: destination_path,
+                            relative: relative.clone(),
+                        });
+                    } else {
+                        stats.files_unchanged += 1;
+                    }
+                }),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            skip_or_abort(options, &relative, e, stats)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Unreadable files and folders and special files are logged and counted rather than aborting the
+/// sync, unless `--strict`. Any other error still aborts.
+fn skip_or_abort(
+    options: &SyncOptions,
+    relative: &Path,
+    e: io::Error,
+    stats: &mut SyncStats,
+) -> io::Result<()> {
+    skip_unreadable(options.strict, relative, e)?;
+    stats.files_skipped += 1;
+    Ok(())
+}
+
+/// Logs a `skippable` error at `relative` and carries on, or returns it under `strict`. Any other
+/// error is returned as it is.
+pub fn skip_unreadable(strict: bool, relative: &Path, e: io::Error) -> io::Result<()> {
+    if !skippable(&e) {
+        return Err(e);
+    }
+    if strict {
+        return Err(io::Error::new(
+            e.kind(),
+            format!(
+                "{}: {}, aborting because of --strict",
+                relative.display(),
+                e
+            ),
+        ));
+    }
+    warn!(target: ERRORS, "skipping {}: {}", relative.display(), e);
+    Ok(())
+}
+
+/// Whether `e` is about a file or folder that cannot be read, or is not a regular file, rather
+/// than a fault that affects everything after it.
+pub fn skippable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+    )
+}
+
+/// Decides whether `destination` has to be (re)written from `source`, by `--compare-by`. The
+/// checksum comparison reuses hashes cached in the manifest for files whose size and mtime are
+/// unchanged.
+fn needs_copy(
+    options: &SyncOptions,
+    source: &Path,
+    destination: &Path,
+    relative: &Path,
+    manifest: &mut Manifest,
+) -> io::Result<bool> {
+    // A transformed or snapshotted network copy never compares equal to the local file, only the
+    // manifest can tell that it is current.
+    if options.copy.stores_differently(relative) {
+        let entry = manifest.entry(relative);
+        let stored = entry.and_then(|entry| entry.stored.as_ref());
+        // A transform may have renamed it.
+        let destination = match stored.and_then(StoredCopy::renamed) {
+            Some(file_name) => destination.with_file_name(file_name),
+            None => destination.to_path_buf(),
+        };
+        let destination_metadata = match fs::metadata(destination) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        if options.compare_by == CompareBy::Existence {
+            return Ok(false);
+        }
+        // An open database's latest changes may only be in its log.
+        if options.copy.sqlite_safe.is_some() && sqlite::has_pending_log(source) {
+            return Ok(true);
+        }
+        let source_metadata = fs::metadata(source)?;
+        return Ok(!entry.is_some_and(|entry| {
+            stored.is_some()
+                && entry.matches(Side::Source, &source_metadata)
+                && entry.matches(Side::Destination, &destination_metadata)
+        }));
+    }
+
+    let destination_metadata = match fs::metadata(destination) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+
+    let equal = compare::files_equal(
+        options.compare_by,
+        source,
+        &fs::metadata(source)?,
+        destination,
+        &destination_metadata,
+        options.verify_unchanged,
+        HashCache {
+            manifest: Some((relative, manifest)),
+            checksums: options.checksum_cache.as_deref(),
+        },
+    )?;
+    Ok(!equal)
+}
+
+/// Whether the destination file was modified after the source one. Copies carry the time they
+/// were written, so a destination that differs from its source and is newer was changed after
+/// the source last was.
+fn newer_on_destination(source_metadata: &fs::Metadata, destination: &Path) -> bool {
+    fs::metadata(destination).is_ok_and(|destination_metadata| {
+        manifest::mtime_ns(&destination_metadata) > manifest::mtime_ns(source_metadata)
+    })
+}
+
+fn copy_job(
+    options: &SyncOptions,
+    job: &FileJob,
+    manifest: &mut Manifest,
+    progress: &mut Progress,
+) -> io::Result<TransferMethod> {
+    let source_metadata = fs::metadata(&job.source)?;
+    let copied = copy::copy_to_network(
+        &job.source,
+        &job.destination,
+        &job.relative,
+        &options.copy,
+        options.verify,
+        &mut |bytes| progress.advance(bytes),
+    )?;
+
+    if options.verify {
+        let recorded = copied
+            .stored
+            .as_ref()
+            .map_or(&[][..], |stored| stored.transforms.as_slice());
+        let written = copy::hash_network_file(
+            &job.destination,
+            &job.relative,
+            &options.copy,
+            true,
+            Some(recorded),
+        )?;
+        if written.sha256 != copied.hashes.sha256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "verification failed, {} does not match {}",
+                    job.destination.display(),
+                    job.source.display()
+                ),
+            ));
+        }
+    }
+
+    manifest.record(
+        &job.relative,
+        ManifestEntry {
+            size: source_metadata.len(),
+            source_mtime_ns: manifest::mtime_ns(&source_metadata),
+            destination_mtime_ns: manifest::mtime_ns(&fs::metadata(&job.destination)?),
+            xxh3: Some(copied.hashes.xxh3),
+            sha256: copied.hashes.sha256,
+            stored: copied.stored,
+        },
+    );
+    Ok(copied.method)
+}
+
+/// Breaks the run down by how each file got, or did not get, to the destination, which shows
+/// whether sparse copies and move detection are kicking in.
+fn log_transfer_methods(stats: &SyncStats) {
+    let mut parts: Vec<String> = stats
+        .by_method
+        .iter()
+        .map(|(method, by_method)| {
+            format!(
+                "{} {} ({})",
+                by_method.files,
+                method,
+                format_bytes(by_method.bytes)
+            )
+        })
+        .collect();
+    parts.push(format!("{} directories renamed", stats.directories_moved));
+    parts.push(format!(
+        "{} empty directories created",
+        stats.directories_created
+    ));
+    parts.push(format!("{} unchanged", stats.files_unchanged));
+    if stats.files_newer_on_destination > 0 {
+        parts.push(format!(
+            "{} newer on the network",
+            stats.files_newer_on_destination
+        ));
+    }
+    parts.push(format!("{} skipped", stats.files_skipped));
+    info!(target: EVENTS, "transfers: {}", parts.join(", "));
+}
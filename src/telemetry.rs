@@ -0,0 +1,190 @@
+use clap::ValueEnum;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use tracing::{error, info, Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Log target of copy progress and the file by file work of syncs and the watcher, the bulk of
+/// the output. `MIN_LEVEL=info,emudeck_sync::progress=off` leaves only the rest.
+pub const PROGRESS: &str = "emudeck_sync::progress";
+
+/// Log target of what a user may want to know about or act on: finished runs and their totals,
+/// conflicts, files left alone, the network coming and going and the time limit running out.
+pub const EVENTS: &str = "emudeck_sync::events";
+
+/// Log target of files that could not be copied, read or deleted. Failures that end the run keep
+/// the target of the module that reports them, like every other log line.
+pub const ERRORS: &str = "emudeck_sync::errors";
+
+/// Log output `--quiet-unchanged` holds back until the run turns out to have changed something,
+/// `None` once it is written as it comes.
+static HELD_OUTPUT: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// How much output is held back at most. A run, or a watch that stays quiet for long, that logs
+/// more is written out from then on as if it had changed something.
+const MAX_HELD_OUTPUT: usize = 1024 * 1024;
+
+/// What `--quiet-unchanged` prints for a run that changed nothing.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuietUnchanged {
+    /// A single line saying so
+    Summary,
+    /// Nothing at all
+    Silent,
+}
+
+/// Writes the log output that was held back, and everything after it as it comes. Called once a
+/// run has copied, deleted or failed something, and for every warning and error.
+pub fn release_output() {
+    let held = HELD_OUTPUT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take();
+    if let Some(held) = held {
+        let _ = io::stdout().write_all(&held);
+    }
+}
+
+/// Stdout, or the held output while there is any.
+struct Output;
+
+struct OutputWriter;
+
+impl<'a> MakeWriter<'a> for Output {
+    type Writer = OutputWriter;
+
+    fn make_writer(&'a self) -> OutputWriter {
+        OutputWriter
+    }
+
+    fn make_writer_for(&'a self, metadata: &Metadata<'_>) -> OutputWriter {
+        if *metadata.level() <= Level::WARN {
+            release_output();
+        }
+        OutputWriter
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut held = HELD_OUTPUT
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match held.as_mut() {
+            Some(held) if held.len() + buf.len() <= MAX_HELD_OUTPUT => {
+                held.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            Some(_) => {
+                let mut stdout = io::stdout();
+                if let Some(held) = held.take() {
+                    stdout.write_all(&held)?;
+                }
+                stdout.write(buf)
+            }
+            None => io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Keeps the trace exporter alive for the run. Call `shutdown` before exiting so buffered spans
+/// are flushed to the collector.
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    quiet_unchanged: Option<QuietUnchanged>,
+}
+
+/// Sets up logging to stdout, filtered by the `MIN_LEVEL` environment variable (default `info`,
+/// with the usual `target=level` directives, for `PROGRESS`, `EVENTS` and `ERRORS` too) and
+/// coloured unless `STYLE=never`. With an OTLP endpoint, spans are also exported as traces over
+/// OTLP/HTTP. With `quiet_unchanged`, output is held back until `release_output`.
+pub fn init(otlp_endpoint: Option<&str>, quiet_unchanged: Option<QuietUnchanged>) -> Telemetry {
+    if quiet_unchanged.is_some() {
+        *HELD_OUTPUT
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Vec::new());
+    }
+
+    let exporter = otlp_endpoint.map(|endpoint| {
+        SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+    });
+    let (tracer_provider, exporter_error) = match exporter {
+        Some(Ok(exporter)) => (
+            Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(
+                        Resource::builder()
+                            .with_service_name(clap::crate_name!())
+                            .build(),
+                    )
+                    .build(),
+            ),
+            None,
+        ),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    let filter = EnvFilter::try_from_env("MIN_LEVEL").unwrap_or_else(|_| EnvFilter::new("info"));
+    let ansi = std::env::var("STYLE").map_or(true, |style| style != "never");
+    let otel_layer = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(clap::crate_name!()))
+    });
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(Output)
+                .with_ansi(ansi),
+        )
+        .with(otel_layer)
+        .init();
+
+    if let Some(e) = exporter_error {
+        error!(
+            "could not set up the OTLP exporter, traces are disabled: {:?}",
+            e
+        );
+    } else if let Some(endpoint) = otlp_endpoint {
+        info!("exporting traces to {endpoint}");
+    }
+
+    Telemetry {
+        tracer_provider,
+        quiet_unchanged,
+    }
+}
+
+impl Telemetry {
+    /// Drops the output still held back, as the run changed nothing, then flushes the traces.
+    pub fn shutdown(self) {
+        let held = HELD_OUTPUT
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if held.is_some() && self.quiet_unchanged == Some(QuietUnchanged::Summary) {
+            info!(target: EVENTS, "nothing changed, everything was already in sync");
+        }
+
+        if let Some(provider) = self.tracer_provider {
+            if let Err(e) = provider.shutdown() {
+                error!("could not flush traces: {:?}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// One step files take on their way to the network, such as `encrypt`, undone on the way back.
+pub trait Transform: Send + Sync {
+    /// What the config file's `transforms` list calls it.
+    fn name(&self) -> &'static str;
+
+    /// Whether it applies to the file at `path`. Asked with the path `apply` is given on the way
+    /// to the network and with the one it returned on the way back, so a transform that renames
+    /// has to recognise both.
+    fn covers(&self, path: &Path) -> bool;
+
+    /// Transforms the content of the file at `path`, returning the path to store the result under
+    /// and the content to store. Only the file name may change, so that it can be undone from the
+    /// stored name alone.
+    fn apply(&self, path: &Path, reader: Box<dyn Read>) -> io::Result<(PathBuf, Box<dyn Read>)>;
+
+    /// Undoes `apply`, from the stored path and content back to the original ones.
+    /// `stored_without` is set when the manifest records that the stored copy did not go through
+    /// this transform, as it was stored before the transform was configured.
+    fn reverse(
+        &self,
+        path: &Path,
+        reader: Box<dyn Read>,
+        stored_without: bool,
+    ) -> io::Result<(PathBuf, Box<dyn Read>)>;
+}
+
+/// The transforms of the config file's `transforms` list, in its order: each one's output is the
+/// next one's input on the way to the network, and the last one is undone first on the way back.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    transforms: Vec<Arc<dyn Transform>>,
+}
+
+/// A file on its way through the pipeline.
+pub struct Transformed {
+    pub path: PathBuf,
+    pub reader: Box<dyn Read>,
+    /// Names of the transforms that covered it, in pipeline order.
+    pub applied: Vec<String>,
+}
+
+impl Pipeline {
+    pub fn new(transforms: Vec<Arc<dyn Transform>>) -> Self {
+        Pipeline { transforms }
+    }
+
+    /// Whether any transform covers `relative`, so that its network copy cannot be compared with
+    /// the local file directly.
+    pub fn covers(&self, relative: &Path) -> bool {
+        self.transforms
+            .iter()
+            .any(|transform| transform.covers(relative))
+    }
+
+    /// Runs the file at `relative` through every transform that covers it, first to last.
+    pub fn apply(&self, relative: &Path, reader: Box<dyn Read>) -> io::Result<Transformed> {
+        self.run(
+            relative,
+            reader,
+            self.transforms.iter(),
+            |transform, path, reader| transform.apply(path, reader),
+        )
+    }
+
+    /// Undoes `apply` for the file stored at `relative`, last transform first. `recorded` are the
+    /// transforms the manifest recorded for the stored copy, `None` when it has no record of it.
+    pub fn reverse(
+        &self,
+        relative: &Path,
+        reader: Box<dyn Read>,
+        recorded: Option<&[String]>,
+    ) -> io::Result<Transformed> {
+        let mut transformed = self.run(
+            relative,
+            reader,
+            self.transforms.iter().rev(),
+            |transform, path, reader| {
+                let stored_without = recorded
+                    .is_some_and(|names| !names.iter().any(|name| name == transform.name()));
+                transform.reverse(path, reader, stored_without)
+            },
+        )?;
+        transformed.applied.reverse();
+        Ok(transformed)
+    }
+
+    fn run<'a>(
+        &self,
+        relative: &Path,
+        reader: Box<dyn Read>,
+        transforms: impl Iterator<Item = &'a Arc<dyn Transform>>,
+        step: impl Fn(&dyn Transform, &Path, Box<dyn Read>) -> io::Result<(PathBuf, Box<dyn Read>)>,
+    ) -> io::Result<Transformed> {
+        let mut transformed = Transformed {
+            path: relative.to_path_buf(),
+            reader,
+            applied: Vec::new(),
+        };
+        for transform in transforms {
+            if !transform.covers(&transformed.path) {
+                continue;
+            }
+            let (path, reader) = step(transform.as_ref(), &transformed.path, transformed.reader)?;
+            if path.parent() != transformed.path.parent() {
+                return Err(io::Error::other(format!(
+                    "transform {} moved {} to {}, only renaming is allowed",
+                    transform.name(),
+                    transformed.path.display(),
+                    path.display()
+                )));
+            }
+            transformed.path = path;
+            transformed.reader = reader;
+            transformed.applied.push(transform.name().to_string());
+        }
+        Ok(transformed)
+    }
+}
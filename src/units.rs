@@ -0,0 +1,110 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Renders a byte count with a binary unit, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Renders a byte count in the largest binary unit that divides it, e.g. `2MiB`, which
+/// `parse_bytes` reads back exactly.
+pub fn format_bytes_exact(bytes: u64) -> String {
+    [("GiB", 30), ("MiB", 20), ("KiB", 10)]
+        .into_iter()
+        .find(|(_, shift)| bytes > 0 && bytes.is_multiple_of(1 << shift))
+        .map_or_else(
+            || bytes.to_string(),
+            |(unit, shift)| format!("{}{}", bytes >> shift, unit),
+        )
+}
+
+/// Parses a byte count such as `512`, `500K`, `2MiB` or `1G`. Units are binary, with or without
+/// the `iB`/`B` suffix.
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits_end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (digits, unit) = value.split_at(digits_end);
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "{value} is not a size, expected e.g. 500K, 2MiB or 1G"
+            ))
+        }
+    };
+    match digits.parse::<f64>() {
+        Ok(count) if count >= 0.0 => Ok((count * multiplier as f64) as u64),
+        _ => Err(format!(
+            "{value} is not a size, expected e.g. 500K, 2MiB or 1G"
+        )),
+    }
+}
+
+/// Parses a duration such as `90`, `90s`, `15m`, `2h` or `1d`. A bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((index, 's')) => (&value[..index], 1),
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 60 * 60),
+        Some((index, 'd')) => (&value[..index], 24 * 60 * 60),
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(count) => count
+            .checked_mul(multiplier)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("{value} is too long a duration")),
+        Err(_) => Err(format!(
+            "{value} is not a duration, expected e.g. 90s, 15m or 2h"
+        )),
+    }
+}
+
+/// Renders a duration to its two largest units, e.g. `2d 3h`, `3h 12m` or `45s`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else if minutes > 0 {
+        format!("{minutes}m {}s", seconds % 60)
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+/// Renders a UTC timestamp as `20241031T134502Z`, which sorts chronologically and is safe to use
+/// as a file name on any filesystem.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+    chrono::DateTime::from_timestamp(seconds as i64, 0)
+        .unwrap_or_default()
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+/// Whether `name` is a timestamp as `format_timestamp` renders them.
+pub fn is_timestamp(name: &str) -> bool {
+    name.len() == 16
+        && name.bytes().enumerate().all(|(index, byte)| match index {
+            8 => byte == b'T',
+            15 => byte == b'Z',
+            _ => byte.is_ascii_digit(),
+        })
+}
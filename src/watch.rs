@@ -0,0 +1,877 @@
+use crate::checksum_cache::ChecksumCache;
+use crate::compare::{self, CompareBy, HashCache};
+use crate::copy::{self, CopyOptions};
+use crate::event_log::{self, EventRecorder};
+use crate::filter::PathFilter;
+use crate::journal::QueueJournal;
+use crate::layout::Layout;
+use crate::manifest::{Manifest, ManifestEntry, Side};
+use crate::network::NetworkMonitor;
+use crate::runtime;
+use crate::status::{self, Direction, RunStatus};
+use crate::telemetry::{self, ERRORS, EVENTS, PROGRESS};
+use crate::units::{format_bytes, format_duration};
+use clap::ValueEnum;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver},
+    future::Fuse,
+    stream::BoxStream,
+    FutureExt, StreamExt,
+};
+use futures_timer::Delay;
+use notify::event::ModifyKind;
+use notify::{Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Span};
+
+/// How often queued paths are copied, and how often an unreachable network directory is
+/// re-checked.
+const QUEUE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the watcher does with queued changes while the network emulation directory is
+/// unreachable.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum NetworkDownAction {
+    /// Hold queued changes until the network directory returns, then copy them
+    Wait,
+    /// Drop changes made while the network directory is down, the next initial sync catches up
+    Skip,
+    /// Exit with the network-unreachable exit code
+    Fail,
+}
+
+/// How changes on the network emulation directory are noticed.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum WatchMode {
+    /// Use the platform's change notifications (inotify, FSEvents, ...)
+    Native,
+    /// Periodically re-scan the tree for size and mtime changes, which also catches writes made
+    /// to an NFS or SMB share by other machines
+    Poll,
+}
+
+pub struct WatchOptions {
+    pub local_root: PathBuf,
+    pub network_root: PathBuf,
+    pub network_down_action: NetworkDownAction,
+    pub watch_mode: WatchMode,
+    pub poll_interval: Duration,
+    /// Paths relative to the network root whose changes are never copied, such as snapshots.
+    pub ignored: Vec<PathBuf>,
+    pub copy: CopyOptions,
+    /// `--include-regex` and `--exclude-regex`.
+    pub filter: PathFilter,
+    pub checksum_cache: Option<Arc<ChecksumCache>>,
+    /// Skips copies the local side already has, except under `existence`, where the event alone
+    /// says the file changed.
+    pub compare_by: CompareBy,
+    /// Watch only the root's own entries, plus `include_folders` recursively.
+    pub non_recursive: bool,
+    /// Folders relative to the network root to watch recursively despite `non_recursive`.
+    pub include_folders: Vec<PathBuf>,
+    /// Append every received event to this JSON lines log.
+    pub record_events: Option<PathBuf>,
+    /// Feed the events of a recorded log through the watcher instead of watching, then stop.
+    pub replay: Option<PathBuf>,
+    /// How often to log a heartbeat while watching, zero never does.
+    pub report_interval: Duration,
+    /// Where each batch is recorded for `--status`.
+    pub state_dir: PathBuf,
+    /// How network paths map back to local ones.
+    pub layout: Layout,
+    /// How many queued paths are copied at once.
+    pub max_concurrent_copies: usize,
+    /// When `--time-limit` runs out and the watcher stops.
+    pub deadline: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub enum WatchError {
+    Notify(notify::Error),
+    NetworkUnreachable(PathBuf),
+    /// The network emulation directory cannot be watched, and why.
+    InvalidRoot(PathBuf, String),
+    /// Recording or replaying an event log failed.
+    EventLog(std::io::Error),
+    /// `--time-limit` ran out with this many changed paths still queued. The queue journal keeps
+    /// them for the next run.
+    TimeLimited(usize),
+}
+
+impl From<notify::Error> for WatchError {
+    fn from(e: notify::Error) -> Self {
+        WatchError::Notify(e)
+    }
+}
+
+/// Changed network paths waiting to be copied to the local side, relative to the network root.
+/// Repeated events for the same file collapse into a single copy.
+#[derive(Default)]
+struct PendingQueue {
+    paths: BTreeSet<PathBuf>,
+    network_down: bool,
+    /// Keeps `paths` across restarts, except while replaying a recorded log.
+    journal: Option<QueueJournal>,
+}
+
+impl PendingQueue {
+    fn push(&mut self, relative: PathBuf) {
+        if let (Some(journal), false) = (self.journal.as_mut(), self.paths.contains(&relative)) {
+            journal.append(&relative);
+        }
+        self.paths.insert(relative);
+    }
+
+    /// Forgets the queue once it has been copied, or dropped by `--network-down-action skip`.
+    fn clear(&mut self) {
+        self.paths.clear();
+        if let Some(journal) = self.journal.as_mut() {
+            journal.clear();
+        }
+    }
+}
+
+/// What the watcher has done since it started, for the heartbeat.
+struct WatchStats {
+    started: Instant,
+    events_handled: u64,
+    files_copied: u64,
+    bytes_copied: u64,
+}
+
+type WatcherAndReceiver = (Box<dyn Watcher>, UnboundedReceiver<notify::Result<Event>>);
+
+type EventStream = futures::stream::Fuse<BoxStream<'static, notify::Result<Event>>>;
+
+/// A started watcher whose events are buffered until `async_watch` gets to them, so it can be
+/// started ahead of the initial sync without missing what changes while that runs.
+pub struct WatchSession {
+    /// Has to be kept alive for as long as its events are wanted.
+    _watcher: Option<Box<dyn Watcher>>,
+    rx: EventStream,
+    recorder: Option<EventRecorder>,
+    journal: Option<(QueueJournal, BTreeSet<PathBuf>)>,
+}
+
+fn async_watcher(options: &WatchOptions) -> notify::Result<WatcherAndReceiver> {
+    // Unbounded, as nothing reads events until the initial sync is done.
+    let (tx, rx) = unbounded();
+    let handler = move |res| {
+        let _ = tx.unbounded_send(res);
+    };
+
+    let watcher: Box<dyn Watcher> = match options.watch_mode {
+        // Automatically select the best implementation for your platform.
+        // You can also access each implementation directly e.g. INotifyWatcher.
+        WatchMode::Native => Box::new(RecommendedWatcher::new(handler, Config::default())?),
+        WatchMode::Poll => {
+            info!(
+                "polling network emulation directory every {:?}",
+                options.poll_interval
+            );
+            Box::new(PollWatcher::new(
+                handler,
+                Config::default().with_poll_interval(options.poll_interval),
+            )?)
+        }
+    };
+
+    Ok((watcher, rx))
+}
+
+/// Starts watching the network emulation directory, or with `--replay` opens the recorded log.
+pub fn start_watch(options: &WatchOptions) -> Result<WatchSession, WatchError> {
+    let (watcher, rx) = event_source(options)?;
+    let recorder = match &options.record_events {
+        Some(path) => {
+            Some(EventRecorder::create(path, &options.network_root).map_err(WatchError::EventLog)?)
+        }
+        None => None,
+    };
+    Ok(WatchSession {
+        _watcher: watcher,
+        rx,
+        recorder,
+        journal: open_journal(options),
+    })
+}
+
+/// Copies what the journal still held from before the last exit, ahead of the watcher and the
+/// initial sync, which would otherwise overwrite those network changes with the local copies.
+pub fn resume_journal(options: &Arc<WatchOptions>) -> Result<(), WatchError> {
+    let Some((journal, pending)) = open_journal(options) else {
+        return Ok(());
+    };
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "resuming {} paths queued for copying before the last exit",
+        pending.len()
+    );
+    let mut queue = PendingQueue {
+        paths: pending,
+        network_down: false,
+        journal: Some(journal),
+    };
+    let mut stats = WatchStats {
+        started: Instant::now(),
+        events_handled: 0,
+        files_copied: 0,
+        bytes_copied: 0,
+    };
+    futures::executor::block_on(drain_queue(
+        options,
+        &Arc::new(Manifest::new()),
+        &Arc::new(NetworkMonitor::new(&options.network_root)),
+        &mut queue,
+        &mut stats,
+    ))
+}
+
+/// The queue journal in the state directory and what it still holds. A journal that cannot be
+/// opened only costs durability, so it is warned about and done without.
+fn open_journal(options: &WatchOptions) -> Option<(QueueJournal, BTreeSet<PathBuf>)> {
+    if options.replay.is_some() {
+        return None;
+    }
+    let path = options.state_dir.join("watch-queue.journal");
+    match QueueJournal::open(&path) {
+        Ok(journal) => Some(journal),
+        Err(e) => {
+            warn!("could not open watch journal {}: {:?}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Copies changes on the network to the local side until the watcher stops. `manifest` holds what
+/// the initial sync left behind, and changes it shows as already identical on both sides, most
+/// of them the sync's own writes, are not copied back.
+pub async fn async_watch(
+    session: WatchSession,
+    options: Arc<WatchOptions>,
+    manifest: Arc<Manifest>,
+) -> Result<(), WatchError> {
+    status::record_watching(&options.state_dir, true);
+    let result = watch_events(session, &options, manifest).await;
+    status::record_watching(&options.state_dir, false);
+    result
+}
+
+async fn watch_events(
+    session: WatchSession,
+    options: &Arc<WatchOptions>,
+    manifest: Arc<Manifest>,
+) -> Result<(), WatchError> {
+    let WatchSession {
+        _watcher,
+        mut rx,
+        mut recorder,
+        journal,
+    } = session;
+    let monitor = Arc::new(NetworkMonitor::new(&options.network_root));
+    let (journal, pending) = journal.unzip();
+    let mut queue = PendingQueue {
+        paths: pending.unwrap_or_default(),
+        network_down: false,
+        journal,
+    };
+    let mut stats = WatchStats {
+        started: Instant::now(),
+        events_handled: 0,
+        files_copied: 0,
+        bytes_copied: 0,
+    };
+
+    let mut tick = Delay::new(QUEUE_INTERVAL).fuse();
+    let mut heartbeat = heartbeat_timer(options);
+    let mut time_limit = time_limit_timer(options);
+    loop {
+        futures::select! {
+            res = rx.next() => match res {
+                Some(Ok(event)) => {
+                    if let Some(recorder) = recorder.as_mut() {
+                        if let Err(e) = recorder.record(&event) {
+                            warn!("could not record event: {:?}", e);
+                        }
+                    }
+                    handle_file_system_event(options, &mut queue, &mut stats, event);
+                }
+                Some(Err(e)) => error!(target: ERRORS, "watch error: {:?}", e),
+                None => break,
+            },
+            () = tick => {
+                tick = Delay::new(QUEUE_INTERVAL).fuse();
+                drain_queue(options, &manifest, &monitor, &mut queue, &mut stats).await?;
+            }
+            () = heartbeat => {
+                heartbeat = heartbeat_timer(options);
+                info!(target: PROGRESS,
+                    "watching for {}: {} events handled, {} files ({}) copied, {} queued",
+                    format_duration(stats.started.elapsed()),
+                    stats.events_handled,
+                    stats.files_copied,
+                    format_bytes(stats.bytes_copied),
+                    queue.paths.len()
+                );
+            }
+            () = time_limit => {
+                if !queue.paths.is_empty() {
+                    return Err(WatchError::TimeLimited(queue.paths.len()));
+                }
+                info!(target: EVENTS, "time limit reached, stopping the watcher");
+                return Ok(());
+            }
+        }
+    }
+
+    // Whatever the last events queued, such as the tail of a replay.
+    drain_queue(options, &manifest, &monitor, &mut queue, &mut stats).await
+}
+
+/// Starts the watcher, or with `--replay` a stream of the recorded events instead. The watcher
+/// has to be kept alive for as long as its events are wanted.
+fn event_source(
+    options: &WatchOptions,
+) -> Result<(Option<Box<dyn Watcher>>, EventStream), WatchError> {
+    if let Some(log) = &options.replay {
+        info!("replaying events from {}", log.display());
+        let events = event_log::load(log).map_err(WatchError::EventLog)?;
+        let stream = event_log::replay(events, &options.network_root);
+        return Ok((None, stream.fuse()));
+    }
+
+    // The initial sync would create it, but it has to exist before it can be watched.
+    if !options.network_root.exists() {
+        info!(
+            "network emulation directory: {} does not exist, creating",
+            options.network_root.display()
+        );
+        copy::create_dir_all(&options.network_root, &options.copy).map_err(|e| {
+            WatchError::InvalidRoot(
+                options.network_root.clone(),
+                format!("could not create it: {e}"),
+            )
+        })?;
+    }
+
+    let root = watch_root(&options.network_root)?;
+    let (mut watcher, rx) = async_watcher(options)?;
+    info!("starting network emulation directory watcher...");
+
+    for (path, mode) in watch_targets(options) {
+        if mode == RecursiveMode::Recursive && path != options.network_root && !path.is_dir() {
+            warn!(
+                "included folder {} does not exist, not watching it",
+                path.display()
+            );
+            continue;
+        }
+        let target = match path.strip_prefix(&options.network_root) {
+            Ok(relative) => root.join(relative),
+            Err(_) => path,
+        };
+        watcher.watch(&target, mode)?;
+    }
+
+    // Events arrive below the canonical root, the rest of the watcher and the initial sync know it
+    // by the name it was given.
+    let network_root = options.network_root.clone();
+    let rx = rx.map(move |event| {
+        event.map(|mut event| {
+            for path in &mut event.paths {
+                if let Ok(relative) = path.strip_prefix(&root) {
+                    *path = network_root.join(relative);
+                }
+            }
+            event
+        })
+    });
+    Ok((Some(watcher), rx.boxed().fuse()))
+}
+
+/// Resolves the network emulation directory to the canonical path to watch, with an error that
+/// says what is wrong rather than the one `notify` would give for a missing path or a file.
+fn watch_root(network_root: &Path) -> Result<PathBuf, WatchError> {
+    let root = fs::canonicalize(network_root).map_err(|e| {
+        WatchError::InvalidRoot(
+            network_root.to_path_buf(),
+            format!("{e}, check the path for typos and that the share is mounted"),
+        )
+    })?;
+    if !root.is_dir() {
+        return Err(WatchError::InvalidRoot(
+            root,
+            "it is not a directory".to_string(),
+        ));
+    }
+    if root != network_root {
+        info!("watching {} as {}", network_root.display(), root.display());
+    }
+    Ok(root)
+}
+
+/// What gets watched and how. Normally all files and directories below the network root, with
+/// `--non-recursive` only the root's own entries plus each included folder's tree.
+pub fn watch_targets(options: &WatchOptions) -> Vec<(PathBuf, RecursiveMode)> {
+    if !options.non_recursive {
+        return vec![(options.network_root.clone(), RecursiveMode::Recursive)];
+    }
+
+    let mut targets = vec![(options.network_root.clone(), RecursiveMode::NonRecursive)];
+    targets.extend(
+        options
+            .include_folders
+            .iter()
+            .map(|folder| (options.network_root.join(folder), RecursiveMode::Recursive)),
+    );
+    targets
+}
+
+/// Fires after `--report-interval`, or never if it is zero.
+fn heartbeat_timer(options: &WatchOptions) -> Fuse<Delay> {
+    if options.report_interval.is_zero() {
+        Fuse::terminated()
+    } else {
+        Delay::new(options.report_interval).fuse()
+    }
+}
+
+fn time_limit_timer(options: &WatchOptions) -> Fuse<Delay> {
+    match options.deadline {
+        Some(deadline) => Delay::new(deadline.saturating_duration_since(Instant::now())).fuse(),
+        None => Fuse::terminated(),
+    }
+}
+
+fn handle_file_system_event(
+    options: &WatchOptions,
+    queue: &mut PendingQueue,
+    stats: &mut WatchStats,
+    event: Event,
+) {
+    info!("event: {:?}", event);
+    stats.events_handled += 1;
+
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+        return;
+    }
+    // A directory only needs copying when it is new, or moved in, not whenever its entries change.
+    let new_path = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+    );
+    for path in event.paths {
+        if !new_path && path.is_dir() {
+            continue;
+        }
+        let Ok(relative) = path.strip_prefix(&options.network_root) else {
+            continue;
+        };
+        if options
+            .ignored
+            .iter()
+            .any(|ignored| relative.starts_with(ignored))
+        {
+            continue;
+        }
+        queue.push(relative.to_path_buf());
+    }
+}
+
+/// Copies every queued path to the local side, or applies the network-down policy if the network
+/// directory has gone away. The copies run on a blocking thread, off the runtime.
+async fn drain_queue(
+    options: &Arc<WatchOptions>,
+    manifest: &Arc<Manifest>,
+    monitor: &Arc<NetworkMonitor>,
+    queue: &mut PendingQueue,
+    stats: &mut WatchStats,
+) -> Result<(), WatchError> {
+    if queue.paths.is_empty() && !queue.network_down {
+        return Ok(());
+    }
+
+    if !monitor.is_available() {
+        return network_down(options, queue);
+    }
+    if queue.network_down {
+        queue.network_down = false;
+        info!(target: EVENTS,
+            "network emulation directory is available again, {} queued paths to copy",
+            queue.paths.len()
+        );
+    }
+
+    let span = info_span!("watch_batch", paths = queue.paths.len());
+    let (drained, mut batch, network_lost) = runtime::blocking({
+        let (options, manifest, monitor) = (
+            Arc::clone(options),
+            Arc::clone(manifest),
+            Arc::clone(monitor),
+        );
+        let mut queue = mem::take(queue);
+        let span = span.clone();
+        move || {
+            let (batch, network_lost) =
+                copy_batch(&options, &manifest, &monitor, &mut queue, &span);
+            (queue, batch, network_lost)
+        }
+    })
+    .await;
+    *queue = drained;
+
+    let _span = span.entered();
+    if network_lost {
+        return network_down(options, queue);
+    }
+    queue.clear();
+    stats.files_copied += batch.files_copied;
+    stats.bytes_copied += batch.bytes_copied;
+    // A batch of nothing but the initial sync's own writes would hide what that sync did.
+    if batch.files_copied > 0 || batch.files_failed > 0 {
+        telemetry::release_output();
+        batch.finished_at = status::now();
+        status::record_run(&options.state_dir, batch);
+    }
+    if let Some(cache) = &options.checksum_cache {
+        cache.save();
+    }
+    Ok(())
+}
+
+/// Copies the queued paths with up to `--max-concurrent-network-events` workers, leaving in the
+/// queue what a lost network directory stopped. Returns the batch and whether that happened.
+fn copy_batch(
+    options: &WatchOptions,
+    manifest: &Manifest,
+    monitor: &NetworkMonitor,
+    queue: &mut PendingQueue,
+    span: &Span,
+) -> (RunStatus, bool) {
+    let workers = options
+        .max_concurrent_copies
+        .clamp(1, queue.paths.len().max(1));
+    let drain = Mutex::new(Drain {
+        queue,
+        batch: RunStatus {
+            finished_at: 0,
+            direction: Direction::Pull,
+            files_copied: 0,
+            bytes_copied: 0,
+            files_failed: 0,
+            files_deleted: 0,
+        },
+        copying: 0,
+        saturated: false,
+        network_lost: false,
+    });
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| span.in_scope(|| copy_worker(options, manifest, monitor, &drain)));
+        }
+    });
+
+    let Drain {
+        batch,
+        network_lost,
+        ..
+    } = drain
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    (batch, network_lost)
+}
+
+/// A batch being copied, shared by its workers.
+struct Drain<'a> {
+    queue: &'a mut PendingQueue,
+    batch: RunStatus,
+    /// How many workers are copying right now.
+    copying: usize,
+    /// Whether this batch already logged that every worker was busy.
+    saturated: bool,
+    /// Set once a copy failed because the network directory went away, which stops the batch.
+    network_lost: bool,
+}
+
+/// Takes queued paths and copies them until none are left. `--max-concurrent-network-events` of
+/// these run at once, so a burst of changes cannot swamp the NAS.
+fn copy_worker(
+    options: &WatchOptions,
+    manifest: &Manifest,
+    monitor: &NetworkMonitor,
+    drain: &Mutex<Drain>,
+) {
+    let lock = || {
+        drain
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    };
+    loop {
+        let relative = {
+            let mut state = lock();
+            if state.network_lost {
+                return;
+            }
+            let Some(relative) = state.queue.paths.pop_first() else {
+                return;
+            };
+            state.copying += 1;
+            if state.copying == options.max_concurrent_copies
+                && !state.queue.paths.is_empty()
+                && !state.saturated
+            {
+                state.saturated = true;
+                info!(target: PROGRESS,
+                    "all {} concurrent copies are busy, {} more paths are waiting for one",
+                    options.max_concurrent_copies,
+                    state.queue.paths.len()
+                );
+            }
+            relative
+        };
+
+        let copied = if already_synced(options, manifest, &relative) {
+            Ok(None)
+        } else if options.network_root.join(&relative).is_dir() {
+            directory_entries(options, &relative).map(|children| {
+                let mut state = lock();
+                for child in children {
+                    state.queue.push(child);
+                }
+                None
+            })
+        } else {
+            copy_to_local(options, manifest, &relative)
+        };
+        let lost = copied.is_err() && !monitor.is_available();
+
+        let mut state = lock();
+        state.copying -= 1;
+        match copied {
+            Ok(Some(bytes)) => {
+                state.batch.files_copied += 1;
+                state.batch.bytes_copied += bytes;
+            }
+            Ok(None) => {}
+            Err(_) if lost => {
+                state.queue.paths.insert(relative);
+                state.network_lost = true;
+            }
+            Err(e) => {
+                error!(target: ERRORS, "watch copy error for {}: {:?}", relative.display(), e);
+                state.batch.files_failed += 1;
+            }
+        }
+    }
+}
+
+/// Creates a new network directory locally and returns everything in it, to be queued. A
+/// directory moved into the tree in one piece arrives as a single event, with none for the files
+/// it already held.
+fn directory_entries(options: &WatchOptions, relative: &Path) -> std::io::Result<Vec<PathBuf>> {
+    copy::create_dir_all(
+        &options.local_root.join(options.layout.to_local(relative)),
+        &options.copy,
+    )?;
+    let mut children = Vec::new();
+    for entry in fs::read_dir(options.network_root.join(relative))? {
+        let child = relative.join(entry?.file_name());
+        if !options
+            .ignored
+            .iter()
+            .any(|ignored| child.starts_with(ignored))
+        {
+            children.push(child);
+        }
+    }
+    Ok(children)
+}
+
+/// Whether both copies of `relative` are still exactly as the manifest recorded them, in which
+/// case its event changed nothing that needs copying.
+fn already_synced(options: &WatchOptions, manifest: &Manifest, relative: &Path) -> bool {
+    let local_relative = options.layout.to_local(relative);
+    let Some(entry) = manifest.entry(&local_relative) else {
+        return false;
+    };
+    match (
+        fs::metadata(options.local_root.join(local_relative)),
+        fs::metadata(options.network_root.join(relative)),
+    ) {
+        (Ok(local), Ok(network)) => {
+            entry.matches(Side::Source, &local) && entry.matches(Side::Destination, &network)
+        }
+        _ => false,
+    }
+}
+
+fn network_down(options: &WatchOptions, queue: &mut PendingQueue) -> Result<(), WatchError> {
+    match options.network_down_action {
+        NetworkDownAction::Wait => {
+            if !queue.network_down {
+                warn!(target: EVENTS,
+                    "network emulation directory {} is unavailable, holding queued changes until it returns",
+                    options.network_root.display()
+                );
+            }
+        }
+        NetworkDownAction::Skip => {
+            if !queue.network_down {
+                warn!(target: EVENTS,
+                    "network emulation directory {} is unavailable, skipping changes until it returns",
+                    options.network_root.display()
+                );
+            }
+            queue.clear();
+        }
+        NetworkDownAction::Fail => {
+            return Err(WatchError::NetworkUnreachable(options.network_root.clone()));
+        }
+    }
+    queue.network_down = true;
+    Ok(())
+}
+
+/// Copies a changed network file to the local side. Returns the bytes copied, or `None` if the
+/// path is no longer a file or the local side already has it.
+fn copy_to_local(
+    options: &WatchOptions,
+    manifest: &Manifest,
+    relative: &Path,
+) -> std::io::Result<Option<u64>> {
+    let source = options.network_root.join(relative);
+    if !source.is_file() {
+        return Ok(None);
+    }
+
+    let local_relative = options.layout.to_local(relative);
+    if !options.filter.allows(&local_relative) {
+        return Ok(None);
+    }
+    let destination = options.local_root.join(&local_relative);
+    // A transformed or snapshotted network copy only ever differs from the local file,
+    // `already_synced` is what spots the unchanged ones.
+    let transformed = options.copy.stores_differently(&local_relative);
+    if options.compare_by != CompareBy::Existence && !transformed {
+        if let Ok(destination_metadata) = fs::metadata(&destination) {
+            if compare::files_equal(
+                options.compare_by,
+                &source,
+                &fs::metadata(&source)?,
+                &destination,
+                &destination_metadata,
+                false,
+                HashCache {
+                    manifest: None,
+                    checksums: options.checksum_cache.as_deref(),
+                },
+            )? {
+                return Ok(None);
+            }
+        }
+    }
+
+    info!(target: PROGRESS, "copying {} to {}", source.display(), destination.display());
+    let mut bytes = 0;
+    copy::copy_from_network(
+        &source,
+        &destination,
+        &local_relative,
+        &options.copy,
+        false,
+        manifest
+            .entry(&local_relative)
+            .map(ManifestEntry::stored_transforms),
+        &mut |chunk| bytes += chunk,
+    )?;
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::RenameMode;
+
+    fn options(local_root: &Path, network_root: &Path, state_dir: &Path) -> WatchOptions {
+        WatchOptions {
+            local_root: local_root.to_path_buf(),
+            network_root: network_root.to_path_buf(),
+            network_down_action: NetworkDownAction::Fail,
+            watch_mode: WatchMode::Native,
+            poll_interval: Duration::from_secs(1),
+            ignored: Vec::new(),
+            copy: CopyOptions::default(),
+            filter: PathFilter::default(),
+            checksum_cache: None,
+            compare_by: CompareBy::Existence,
+            non_recursive: false,
+            include_folders: Vec::new(),
+            record_events: None,
+            replay: None,
+            report_interval: Duration::ZERO,
+            state_dir: state_dir.to_path_buf(),
+            layout: Layout::default(),
+            max_concurrent_copies: 2,
+            deadline: None,
+        }
+    }
+
+    /// A populated directory moved into the network root arrives as a single rename event, and
+    /// every file below it still has to reach the local side.
+    #[test]
+    fn moved_in_directory_is_copied_recursively() {
+        let local = tempfile::tempdir().unwrap();
+        let network = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let staging = tempfile::tempdir().unwrap();
+        let files = ["a.sav", "sub/b.sav", "sub/deeper/c.sav"];
+        for file in files {
+            let path = staging.path().join("new_system").join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, file).unwrap();
+        }
+        let moved = network.path().join("roms/new_system");
+        fs::create_dir_all(moved.parent().unwrap()).unwrap();
+        fs::rename(staging.path().join("new_system"), &moved).unwrap();
+
+        let options = Arc::new(options(local.path(), network.path(), state.path()));
+        let mut queue = PendingQueue::default();
+        let mut stats = WatchStats {
+            started: Instant::now(),
+            events_handled: 0,
+            files_copied: 0,
+            bytes_copied: 0,
+        };
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::To))).add_path(moved);
+        handle_file_system_event(&options, &mut queue, &mut stats, event);
+        futures::executor::block_on(drain_queue(
+            &options,
+            &Arc::new(Manifest::new()),
+            &Arc::new(NetworkMonitor::new(network.path())),
+            &mut queue,
+            &mut stats,
+        ))
+        .unwrap();
+
+        for file in files {
+            let copied = local.path().join("roms/new_system").join(file);
+            assert_eq!(
+                fs::read_to_string(&copied).unwrap(),
+                file,
+                "{}",
+                copied.display()
+            );
+        }
+        assert_eq!(stats.files_copied, files.len() as u64);
+        assert!(queue.paths.is_empty());
+    }
+}
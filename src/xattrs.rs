@@ -0,0 +1,73 @@
+use crate::telemetry::ERRORS;
+use std::path::Path;
+use tracing::warn;
+
+/// Prefix of the attributes Linux keeps POSIX ACLs in, which only `--acls` copies.
+#[cfg(unix)]
+const ACL_PREFIX: &str = "system.posix_acl_";
+
+/// Extended attributes only exist on Unix, so `--xattrs` is ignored elsewhere.
+pub fn check_supported(xattrs: bool) {
+    if cfg!(not(unix)) && xattrs {
+        warn!("--xattrs is only supported on Unix, ignoring");
+    }
+}
+
+/// Gives `destination` the extended attributes of `source`, POSIX ACLs only with `acls`, and
+/// removes the ones `source` does not have. The content is already copied by then, so an
+/// attribute the destination refuses, as a filesystem without them does, only gets a warning.
+#[cfg(unix)]
+pub fn copy_attributes(source: &Path, destination: &Path, acls: bool) {
+    let copied = |name: &std::ffi::OsStr| acls || !name.to_string_lossy().starts_with(ACL_PREFIX);
+    let names: Vec<_> = match xattr::list_deref(source) {
+        Ok(names) => names.filter(|name| copied(name)).collect(),
+        Err(e) => {
+            warn!(
+                target: ERRORS,
+                "could not read extended attributes of {}: {:?}",
+                source.display(),
+                e
+            );
+            return;
+        }
+    };
+
+    for name in &names {
+        // `None` if it was removed since it was listed.
+        let result = xattr::get_deref(source, name).and_then(|value| match value {
+            Some(value) => xattr::set_deref(destination, name, &value),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            warn!(
+                target: ERRORS,
+                "could not copy extended attribute {} to {}: {:?}",
+                name.to_string_lossy(),
+                destination.display(),
+                e
+            );
+            return;
+        }
+    }
+
+    // Replacing a file's content keeps the attributes it had.
+    let stale = match xattr::list_deref(destination) {
+        Ok(existing) => existing.filter(|name| copied(name) && !names.contains(name)),
+        Err(_) => return,
+    };
+    for name in stale {
+        if let Err(e) = xattr::remove_deref(destination, &name) {
+            warn!(
+                target: ERRORS,
+                "could not remove extended attribute {} from {}: {:?}",
+                name.to_string_lossy(),
+                destination.display(),
+                e
+            );
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn copy_attributes(_source: &Path, _destination: &Path, _acls: bool) {}